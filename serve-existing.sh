@@ -1,3 +1,3 @@
 #!/usr/bin/bash
-cargo run --release --features server
+cargo run --release --features server -- "$@"
 
@@ -0,0 +1,60 @@
+// #[sides(server, client#selfhost)]
+
+use rusqlite::Connection;
+use anyhow::Result;
+
+use crate::crosstyping::*;
+
+
+/// Categories of `principal` (`None` for self-hosted ones), in the order they are offered.
+pub fn categories_of(conn: &Connection, principal: Option<&str>) -> Result<Vec<Category>> {
+    let categories = conn.prepare("
+SELECT name, icon, color FROM categories
+    WHERE principal IS ?1
+    ORDER BY sort_order, name;
+        ")?.query_map((principal,), |row| {
+            let color: u32 = row.get(2)?;
+            let [_, r, g, b] = color.to_be_bytes();
+            Ok(Category {name: row.get(0)?, icon: row.get(1)?, color: [r, g, b]})
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(categories)
+}
+
+/// Stores `categories` as the whole list of `principal`'s ones, in their order. Caller checks
+/// them with `categories_valid` and runs this within a transaction.
+pub fn replace_categories(conn: &Connection, principal: Option<&str>, categories: &[Category]) -> Result<()> {
+    conn.execute("DELETE FROM categories WHERE principal IS ?1", (principal,))?;
+    let mut insert = conn.prepare("
+INSERT INTO categories(principal, name, icon, color, sort_order) VALUES(?1, ?2, ?3, ?4, ?5);
+        ")?;
+    for (i, c) in categories.iter().enumerate() {
+        let [r, g, b] = c.color;
+        insert.execute((principal, &c.name, &c.icon, u32::from_be_bytes([0, r, g, b]), i))?;
+    }
+    Ok(())
+}
+
+/// Monthly budgets of `principal` (`None` for self-hosted ones), overall one first.
+pub fn budgets_of(conn: &Connection, principal: Option<&str>) -> Result<Vec<Budget>> {
+    let budgets = conn.prepare("
+SELECT category, limit_base FROM budgets
+    WHERE principal IS ?1
+    ORDER BY category IS NOT NULL, category;
+        ")?.query_map((principal,), |row| Ok(Budget {category: row.get(0)?, limit: row.get(1)?}))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(budgets)
+}
+
+/// Stores `budgets` as the whole list of `principal`'s ones. Caller checks them with
+/// `budgets_valid` and runs this within a transaction.
+pub fn replace_budgets(conn: &Connection, principal: Option<&str>, budgets: &[Budget]) -> Result<()> {
+    conn.execute("DELETE FROM budgets WHERE principal IS ?1", (principal,))?;
+    let mut insert = conn.prepare("
+INSERT INTO budgets(principal, category, limit_base) VALUES(?1, ?2, ?3);
+        ")?;
+    for b in budgets {
+        insert.execute((principal, &b.category, b.limit))?;
+    }
+    Ok(())
+}
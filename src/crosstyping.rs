@@ -1,12 +1,73 @@
 // #[sides(client, server)]
 
-use time::{Duration, OffsetDateTime, format_description::well_known::Rfc3339};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 
 pub const UNCLASSIFIED: &str = "покупки";
-pub const MONTH_LIKE: Duration = Duration::days(30);
+/// ISO 4217 code of the currency all stats are kept in.
+pub const BASE_CURRENCY: &str = "RUB";
+
+/// Sign to display next to amounts in given currency.
+pub fn currency_sign(code: &str) -> &str {
+    match code {
+        "RUB" => "\u{20bd}",
+        "EUR" => "\u{20ac}",
+        "USD" => "$",
+        "GBP" => "\u{a3}",
+        "CNY" | "JPY" => "\u{a5}",
+        other => other,
+    }
+}
+
+/// Whether `code` looks like an ISO 4217 currency code.
+pub fn is_currency_code(code: &str) -> bool {
+    code.len() == 3 && code.bytes().all(|b| b.is_ascii_uppercase())
+}
+
+/// ISO 4217 exponent: how many decimal digits minor unit of a currency takes.
+pub fn currency_exponent(code: &str) -> u32 {
+    match code {
+        "BIF" | "CLP" | "DJF" | "GNF" | "ISK" | "JPY" | "KMF" | "KRW" | "PYG" | "RWF" | "UGX" |
+        "UYI" | "VND" | "VUV" | "XAF" | "XOF" | "XPF" => 0,
+        "BHD" | "IQD" | "JOD" | "KWD" | "LYD" | "OMR" | "TND" => 3,
+        _ => 2,
+    }
+}
+
+/// Formats an amount given in minor units as a decimal, like `249.90`.
+pub fn format_amount(minor: u64, currency: &str) -> String {
+    let exponent = currency_exponent(currency);
+    let scale = 10_u64.pow(exponent);
+    match exponent {
+        0 => minor.to_string(),
+        e => format!("{}.{:0width$}", minor / scale, minor % scale, width = e as usize),
+    }
+}
+
+/// Parses a decimal amount (with either `.` or `,` separator) into minor units.
+/// Amounts more precise than the currency's minor unit are rejected, not rounded.
+pub fn parse_amount(text: &str, currency: &str) -> Option<u64> {
+    let exponent = currency_exponent(currency) as usize;
+    let text = text.trim().replace(',', ".");
+    let (major, minor) = text.split_once('.').unwrap_or((&text, ""));
+    let digits_only = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if major.is_empty() || !digits_only(major) || !digits_only(minor) || minor.len() > exponent {
+        return None;
+    }
+    
+    let major: u64 = major.parse().ok()?;
+    let minor: u64 = format!("{minor:0<exponent$}").parse().unwrap_or(0);
+    major.checked_mul(10_u64.pow(exponent as u32))?.checked_add(minor)
+}
+
+/// Converts an amount in minor units of `currency` into minor units of base currency, at given
+/// rate in base currency units per one unit of `currency`.
+pub fn to_base(amount: u64, currency: &str, base_per_unit: f64) -> u64 {
+    let shift = currency_exponent(BASE_CURRENCY) as i32 - currency_exponent(currency) as i32;
+    (amount as f64 * base_per_unit * 10_f64.powi(shift)).round() as u64
+}
 
 //----------------------------------------------------------------------------//
 /// Server-generated information about a certain expense.
@@ -16,14 +77,104 @@ pub const MONTH_LIKE: Duration = Duration::days(30);
 pub struct Metadata {
     pub uid: Uuid,
     pub time: OffsetDateTime,
-    pub principal: Option<String>    // None stands for local
+    pub principal: Option<String>,   // None stands for local
+    /// Amount in base currency minor units, at the rate stored when the expense was recorded or
+    /// amended.
+    pub base_amount: u64,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ClientData {
+    /// In minor units of `currency`, like kopecks or cents.
     pub amount: u64,
     pub group: Option<String>,
     pub revoked: bool,
+    pub note: Option<String>,
+    pub currency: String,
+    /// Labels cutting across categories, as `normalize_tags` leaves them.
+    pub tags: Vec<String>,
+}
+
+/// Tidies tags: leading `#` is dropped and spaces within become `-`; empty and repeated tags are
+/// dropped, the rest are sorted.
+pub fn normalize_tags<'a>(tags: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut tags: Vec<String> = tags.into_iter()
+        .map(|tag| tag.trim().trim_start_matches('#').split_whitespace().collect::<Vec<_>>().join("-"))
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+/// Reads tags as typed by user, separated by commas or spaces.
+#[cfg(feature = "graphics")]
+pub fn parse_tags(text: &str) -> Vec<String> {
+    normalize_tags(text.split(|c: char| c == ',' || c.is_whitespace()))
+}
+
+/// Parts of a category path, like "транспорт/такси", which is a subcategory of "транспорт".
+pub const SUBCATEGORY_SEPARATOR: char = '/';
+
+/// Tidies a category as typed by user: spaces around parts and empty parts are dropped.
+pub fn normalize_category(text: &str) -> String {
+    text.split(SUBCATEGORY_SEPARATOR).map(str::trim).filter(|part| !part.is_empty())
+        .collect::<Vec<_>>().join(&SUBCATEGORY_SEPARATOR.to_string())
+}
+
+/// Category directly containing `group`, if it is a subcategory.
+pub fn parent_category(group: &str) -> Option<&str> {
+    group.rsplit_once(SUBCATEGORY_SEPARATOR).map(|(parent, _)| parent)
+}
+
+/// Category offered when recording an expense. Expenses keep only its name as their group, so
+/// renaming or removing a category leaves recorded ones as they are.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Category {
+    pub name: String,
+    /// Emoji shown on the category slider.
+    pub icon: String,
+    /// sRGB.
+    pub color: [u8; 3],
+}
+
+/// More would not fit the category slider.
+pub const MAX_CATEGORIES: usize = 10;
+
+/// Whether categories may be stored: at most `MAX_CATEGORIES`, with distinct trimmed names and
+/// some icons.
+pub fn categories_valid(categories: &[Category]) -> bool {
+    let mut names: Vec<&str> = categories.iter().map(|c| c.name.as_str()).collect();
+    names.sort();
+    names.dedup();
+    categories.len() <= MAX_CATEGORIES && names.len() == categories.len() &&
+        categories.iter().all(|c| !c.name.is_empty() && c.name.trim() == c.name && !c.icon.trim().is_empty())
+}
+
+/// Categories everyone starts with.
+#[cfg(feature = "server")]
+pub fn default_categories() -> Vec<Category> {
+    [("еду", "🍞", [0, 255, 0]), ("хозтовары", "🏡", [96, 96, 96]), ("транспорт", "🚋", [255, 165, 0])]
+        .map(|(name, icon, color)| Category {name: name.to_owned(), icon: icon.to_owned(), color})
+        .into()
+}
+
+/// Most which may be spent in a month, in base currency minor units: on a category together with
+/// its subcategories, or on everything if `category` is `None`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Budget {
+    pub category: Option<String>,
+    pub limit: u64,
+}
+
+/// Whether budgets may be stored: nonzero limits, at most one for each category and one overall,
+/// categories as `normalize_category` leaves them.
+pub fn budgets_valid(budgets: &[Budget]) -> bool {
+    let mut categories: Vec<Option<&str>> = budgets.iter().map(|b| b.category.as_deref()).collect();
+    categories.sort();
+    categories.dedup();
+    categories.len() == budgets.len() && budgets.iter().all(|b| b.limit > 0 &&
+        b.category.as_ref().is_none_or(|c| !c.is_empty() && normalize_category(c) == *c))
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -37,15 +188,26 @@ impl std::fmt::Display for Expense {
         if self.client.revoked {
             return Err(std::fmt::Error);
         }
-        write!(f, "{:08X} - {} - {}\u{20bd} на {}",
+        write!(f, "{:08X} - {} - {}{} на {}",
             self.server.uid.as_fields().0,
             self.server.time.format(&Rfc3339).unwrap(),
-            self.client.amount,
+            format_amount(self.client.amount, &self.client.currency),
+            currency_sign(&self.client.currency),
             self.client.group.as_deref().unwrap_or(UNCLASSIFIED)
-        )
+        )?;
+        for tag in &self.client.tags {
+            write!(f, " #{tag}")?;
+        }
+        match &self.client.note {
+            Some(note) => write!(f, " ({note})"),
+            None       => Ok(()),
+        }
     }
 }
 
+/// Total spending in base currency and count of live expenses, then spendings by group.
+pub type GroupTotals = ((u64, usize), Vec<(String, u64)>);
+
 #[cfg(feature = "graphics")]
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct CachedStats {
@@ -77,24 +239,61 @@ impl CachedStats {
         warn(dead_code, reason = "Any +graphics combination must accept incoming expenses")
     )]
     pub fn add(&mut self, e: &Expense) {
-        self.raw_add(e.client.group.as_deref().unwrap_or(UNCLASSIFIED), e.client.amount as i64, 1);
+        self.raw_add(e.client.group.as_deref().unwrap_or(UNCLASSIFIED), e.server.base_amount as i64, 1);
     }
     #[cfg_attr(
         feature = "graphics",
         warn(dead_code, reason = "Any +graphics combination must push month-old expenses out")
     )]
     pub fn sub(&mut self, e: &Expense) {
-        let inv_amount = -(e.client.amount as i64);
+        let inv_amount = -(e.server.base_amount as i64);
         self.raw_add(e.client.group.as_deref().unwrap_or(UNCLASSIFIED), inv_amount, -1);
     }
-    #[allow(dead_code, reason = "+selfhost does not need this as it creates default instances")]
-    pub fn new(records: ((u64, usize), Vec<(String, u64)>)) -> Self {
+    pub fn new(records: GroupTotals) -> Self {
         let ((total_spending, records_alive), group_spendings) = records;
         let group_indices = std::collections::BTreeMap::default();
         let mut this = Self {records_alive, group_spendings, group_indices, total_spending};
         this.set_indices();
         this
     }
+    /// Spendings on `category` and its subcategories, or all of them if `None`.
+    pub fn spent_within(&self, category: Option<&str>) -> u64 {
+        let Some(category) = category else {return self.total_spending};
+        self.group_spendings.iter()
+            .filter(|(group, _)| group.strip_prefix(category)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(SUBCATEGORY_SEPARATOR)))
+            .map(|(_, amount)| amount)
+            .sum()
+    }
+    /// Sums spendings up to the level just below `parent` (top level if `None`): each of its
+    /// subcategories gets totals of its whole subtree, and `parent` itself gets expenses recorded
+    /// right under it. Also tells which of the results have subcategories of their own.
+    pub fn rolled_up(&self, parent: Option<&str>) -> Vec<(String, u64, bool)> {
+        let mut rolled: Vec<(String, u64, bool)> = vec![];
+        for (group, amount) in &self.group_spendings {
+            let (depth, rest) = match parent {
+                None => (0, group.as_str()),
+                Some(parent) if group == parent => (group.len(), ""),
+                Some(parent) => match group.strip_prefix(parent)
+                                             .and_then(|g| g.strip_prefix(SUBCATEGORY_SEPARATOR)) {
+                    Some(rest) => (group.len() - rest.len(), rest),
+                    None => continue,
+                },
+            };
+            let (node, deeper) = match rest.find(SUBCATEGORY_SEPARATOR) {
+                Some(i) => (&group[..depth + i], true),
+                None    => (group.as_str(), false),
+            };
+            match rolled.iter_mut().find(|r| r.0 == node) {
+                Some(r) => {
+                    r.1 += amount;
+                    r.2 |= deeper;
+                },
+                None => rolled.push((node.to_owned(), *amount, deeper)),
+            }
+        }
+        rolled
+    }
     fn set_indices(&mut self) {
         self.group_indices.clear();
         for (i, (g, _)) in self.group_spendings.iter().enumerate() {
@@ -108,16 +307,92 @@ impl CachedStats {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum ClientboundUpdate {
     Revoked {expense: Expense},
+    // Sent only to the requesting client, when there was no live record to revoke.
+    RevokeRejected {expense_id: Uuid},
     NewSpending {expense: Expense, temp_alias: Uuid},
-    InitStats {lifetime_stats: ((u64, usize), Vec<(String, u64)>), recent_expenses: Vec<Expense>},
+    Amended {previous: Expense, expense: Expense},
+    InitStats {lifetime_stats: GroupTotals, recent_expenses: Vec<Expense>,
+               categories: Vec<Category>},
     // Must be adjacent to already-known ones.
     RevealHistory {expenses: Vec<Expense>},
+    // Full table, in base currency units per one unit of each other currency.
+    ExchangeRates {rates: Vec<(String, f64)>},
+    // Full list, in the order they are offered.
+    Categories {categories: Vec<Category>},
+    // Sent only to the requesting client; `total` and count are over all live expenses with the tag.
+    RevealTagged {tag: String, expenses: Vec<Expense>, total: (u64, usize)},
+    // Full list, overall budget first.
+    Budgets {budgets: Vec<Budget>},
+    // Sent only to the requesting client, with its bounds; `stats` are like lifetime ones in
+    // `InitStats`, and tags are counted like in `RevealTagged`.
+    RevealPeriod {from: Option<OffsetDateTime>, to: Option<OffsetDateTime>,
+                  stats: GroupTotals, tags: Vec<(String, u64, usize)>},
+    // Sent only to the submitting client, which must not send `update` again.
+    Rejected {update: ServerboundUpdate, reason: String},
+    Restored {expense: Expense},
 }
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum ServerboundUpdate {
     Revoked {expense_id: Uuid},
-    MadeExpense {info: ClientData, temp_alias: Uuid},
-    QueryHistory {before: OffsetDateTime, amount: usize}
+    // Made at `time` by the client's clock, which may be long before it is delivered.
+    MadeExpense {info: ClientData, temp_alias: Uuid, time: OffsetDateTime},
+    Amend {expense_id: Uuid, info: ClientData, time: OffsetDateTime},
+    // Most recent live expenses preceding `before` in (time, id) order.
+    QueryHistory {before: (OffsetDateTime, Uuid), amount: usize},
+    SetExchangeRate {currency: String, base_per_unit: f64},
+    SetCategories {categories: Vec<Category>},
+    // Most recent live expenses with the tag, like `QueryHistory`.
+    QueryTagged {tag: String, before: OffsetDateTime, amount: usize},
+    SetBudgets {budgets: Vec<Budget>},
+    // Live expenses made in `[from, to)` summed up; open ends are not limited.
+    QueryPeriod {from: Option<OffsetDateTime>, to: Option<OffsetDateTime>},
+    // Undoes revocation of the expense, keeping its ID.
+    Restore {expense_id: Uuid},
+}
+
+/// File formats expenses may be exported in.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Json,
+    /// Plain-text journal for ledger and hledger.
+    Ledger,
+}
+impl ExportFormat {
+    /// Name in `format` query parameter of export requests, as it is deserialized.
+    #[cfg(all(feature = "graphics", not(feature = "selfhost")))]
+    pub fn query_name(self) -> &'static str {
+        match self {
+            ExportFormat::Csv    => "csv",
+            ExportFormat::Json   => "json",
+            ExportFormat::Ledger => "ledger",
+        }
+    }
+    
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv    => "csv",
+            ExportFormat::Json   => "json",
+            ExportFormat::Ledger => "journal",
+        }
+    }
+}
+
+/// Body of `POST /api/sync`: records changed locally, and how far server's changes were seen.
+#[cfg(any(feature = "server", feature = "selfhost"))]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SyncRequest {
+    pub since: u64,
+    pub changes: Vec<Expense>,
+}
+/// Records changed on server after `SyncRequest::since`, including just merged ones, and the
+/// server's change counter to pass as `since` next time.
+#[cfg(any(feature = "server", feature = "selfhost"))]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SyncReply {
+    pub cursor: u64,
+    pub changes: Vec<Expense>,
 }
 
 #[cfg(feature = "graphics")]
@@ -127,6 +402,20 @@ pub trait Upstream {
     
     /// Lifetime stats, month stats, at least month's worth of RECENTMOST confirmed expenses.
     fn take_init(&mut self) -> Option<(CachedStats, CachedStats, Vec<Expense>)>;
+    
+    /// Expenses submitted in earlier runs and not yet confirmed, with times they were made at.
+    fn take_pending(&mut self) -> Vec<(Uuid, ClientData, OffsetDateTime)> {
+        Vec::new()
+    }
+    
+    /// Saves all expenses in `format`, revoked ones too if asked; tells where they were saved, or
+    /// what went wrong.
+    fn export(&mut self, _format: ExportFormat, _include_revoked: bool)
+            -> tokio::sync::oneshot::Receiver<Result<String, String>> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let _ = tx.send(Err("эта сборка не умеет выгружать расходы".to_owned()));
+        rx
+    }
 }
 
 
@@ -145,5 +434,14 @@ impl<U: Upstream + ?Sized> Upstream for Box<U> {
         let content: &mut U = &mut *self;
         content.take_init()
     }
+    fn take_pending(&mut self) -> Vec<(Uuid, ClientData, OffsetDateTime)> {
+        let content: &mut U = &mut *self;
+        content.take_pending()
+    }
+    fn export(&mut self, format: ExportFormat, include_revoked: bool)
+            -> tokio::sync::oneshot::Receiver<Result<String, String>> {
+        let content: &mut U = &mut *self;
+        content.export(format, include_revoked)
+    }
 }
 
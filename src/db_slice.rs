@@ -1,6 +1,7 @@
 // #[sides(client)]
 
-use time::OffsetDateTime;
+use std::collections::BTreeMap;
+use time::{Date, Duration, OffsetDateTime, PrimitiveDateTime, UtcOffset};
 use liquemap::LiqueMap;
 use uuid::Uuid;
 
@@ -13,7 +14,43 @@ pub fn now() -> OffsetDateTime {
 }
 #[cfg(feature = "graphics_wasm")]
 pub fn now() -> OffsetDateTime {
-    js_sys::Date::new_0().into()
+    let js_now = js_sys::Date::new_0();
+    OffsetDateTime::from(js_now.clone()).to_offset(js_offset(&js_now))
+}
+#[cfg(feature = "graphics_wasm")]
+fn js_offset(date: &js_sys::Date) -> UtcOffset {
+    // JS counts minutes from local time to UTC, that is with the opposite sign.
+    UtcOffset::from_whole_seconds(-(date.get_timezone_offset() as i32) * 60).unwrap()
+}
+
+/// Offset of local time at moment `t`, which may differ from today's one.
+#[cfg(not(feature = "graphics_wasm"))]
+pub fn local_offset_at(t: OffsetDateTime) -> UtcOffset {
+    UtcOffset::local_offset_at(t).unwrap()
+}
+#[cfg(feature = "graphics_wasm")]
+pub fn local_offset_at(t: OffsetDateTime) -> UtcOffset {
+    let js_date = js_sys::Date::new_0();
+    js_date.set_time((t.unix_timestamp_nanos() / 1_000_000) as f64);
+    js_offset(&js_date)
+}
+
+/// Local time `t`, with the offset in effect then rather than today's one.
+pub fn assume_local(t: PrimitiveDateTime) -> OffsetDateTime {
+    let guess = local_offset_at(t.assume_utc());
+    t.assume_offset(local_offset_at(t.assume_offset(guess)))
+}
+
+/// Local midnight starting `date`.
+#[cfg(not(feature = "graphics_wasm"))]
+fn local_midnight(date: Date) -> OffsetDateTime {
+    assume_local(date.midnight())
+}
+#[cfg(feature = "graphics_wasm")]
+fn local_midnight(date: Date) -> OffsetDateTime {
+    let month = u8::from(date.month()) as i32 - 1;
+    let js_midnight = js_sys::Date::new_with_year_month_day(date.year() as u32, month, date.day().into());
+    date.midnight().assume_offset(js_offset(&js_midnight))
 }
 
 
@@ -32,29 +69,120 @@ pub enum RecordViewKey {
 }
 pub enum RecordViewValue {
     Confirmed(Expense),
-    Provisional(ClientData, OffsetDateTime)
+    // Also keeps the amount in base currency accounted into stats until confirmation.
+    Provisional(ClientData, OffsetDateTime, u64)
 }
 impl RecordViewValue {
     fn borrow(&self) -> MayLoad<'_> {
         use RecordViewValue::*;
         match self {
             Confirmed(c) => MayLoad::Confirmed(c),
-            Provisional(data, temp_time) => MayLoad::Provisional{data, temp_time: *temp_time},
+            Provisional(data, temp_time, _) => MayLoad::Provisional{data, temp_time: *temp_time},
         }
     }
 }
 
 
+/// Stretch of time stats are shown for, in user's timezone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Period {
+    /// Calendar month, this many before the current one.
+    Month {back: u32},
+    /// Week from Monday, this many before the current one.
+    Week {back: u32},
+    /// From the first day to the last one, both included.
+    Days {first: Date, last: Date},
+    Lifetime,
+}
+impl Period {
+    /// Start of the period and the moment right after it, unless it is unlimited.
+    pub fn bounds(self, now: OffsetDateTime) -> Option<(OffsetDateTime, OffsetDateTime)> {
+        let today = now.date();
+        let (first, after) = match self {
+            Period::Month{back} => {
+                let mut first = today.replace_day(1).unwrap();
+                for _ in 0..back {
+                    first = first.previous_day().unwrap().replace_day(1).unwrap();
+                }
+                (first, first + Duration::days(first.month().length(first.year()).into()))
+            },
+            Period::Week{back} => {
+                let monday = today - Duration::days(today.weekday().number_days_from_monday().into())
+                                   - Duration::weeks(back.into());
+                (monday, monday + Duration::weeks(1))
+            },
+            Period::Days{first, last} => (first, last.next_day().unwrap()),
+            Period::Lifetime => return None,
+        };
+        Some((local_midnight(first), local_midnight(after)))
+    }
+}
+
+impl Default for Period {
+    fn default() -> Self {
+        Period::Month{back: 0}
+    }
+}
+
+/// Stats of some period other than the current month, as upstream counted them.
+struct PeriodStats {
+    from: Option<OffsetDateTime>,
+    to: Option<OffsetDateTime>,
+    stats: CachedStats,
+    tags: Vec<(String, u64, usize)>,
+}
+
+
+/// Monthly budget and how much of it is spent within the month.
+pub struct BudgetUse {
+    pub budget: Budget,
+    pub spent: u64,
+}
+impl BudgetUse {
+    /// Negative once overspent.
+    pub fn remaining(&self) -> i64 {
+        self.budget.limit as i64 - self.spent as i64
+    }
+}
+
+
 pub struct DbView<U: Upstream> {
     upstream: U,
     live_records: LiqueMap<RecordViewKey, RecordViewValue>,
     life_stats: CachedStats,
-    month_stats: CachedStats
+    month_stats: CachedStats,
+    // Revocations already applied to stats here, but not yet confirmed by upstream.
+    pending_revokes: BTreeMap<Uuid, Expense>,
+    last_revoked: Option<Expense>,
+    history_requested: bool,
+    history_exhausted: bool,
+    exchange_rates: BTreeMap<String, f64>,
+    categories: Vec<Category>,
+    // As upstream last told; shown again if it rejects a change made here.
+    accepted_categories: Vec<Category>,
+    budgets: Vec<Budget>,
+    // Like `accepted_categories`.
+    accepted_budgets: Vec<Budget>,
+    // Expenses with a tag and their totals, as upstream last told; dropped on any change.
+    tagged: Option<(String, Vec<Expense>, (u64, usize))>,
+    tagged_requested: Option<String>,
+    // Like `tagged`, for a period; dropped on any change too.
+    period: Option<PeriodStats>,
+    period_requested: Option<(Option<OffsetDateTime>, Option<OffsetDateTime>)>,
+    // Why upstream refused the latest update it did not accept.
+    rejection: Option<String>,
 }
 
+/// How many more expenses than immediately needed are requested from upstream at once.
+const HISTORY_PREFETCH: usize = 50;
+
+/// How many most recent expenses with a tag are shown.
+const TAGGED_SHOWN: usize = 200;
+
 impl<U: Upstream> DbView<U> {
     pub fn with(mut upstream: U) -> Self {
         let (life_stats, month_stats, live_records) = upstream.take_init().unwrap_or_default();
+        let pending = upstream.take_pending();
         
         let mut live_records_map = LiqueMap::new();
         for exp in live_records {
@@ -63,16 +191,42 @@ impl<U: Upstream> DbView<U> {
                 RecordViewValue::Confirmed(exp));
         }
         
-        Self {
+        let mut this = Self {
             upstream,
             live_records: live_records_map,
             life_stats,
             month_stats,
+            pending_revokes: BTreeMap::new(),
+            last_revoked: None,
+            history_requested: false,
+            history_exhausted: false,
+            exchange_rates: BTreeMap::new(),
+            categories: vec![],
+            accepted_categories: vec![],
+            budgets: vec![],
+            accepted_budgets: vec![],
+            tagged: None,
+            tagged_requested: None,
+            period: None,
+            period_requested: None,
+            rejection: None,
+        };
+        
+        // Upstream is resubmitting those; they are shown as if just entered.
+        for (temp_alias, c, t) in pending {
+            let base_amount = this.estimate_base(&c);
+            this.life_stats.raw_add(c.group.as_deref().unwrap_or(UNCLASSIFIED), base_amount as i64, 1);
+            this.month_stats.raw_add(c.group.as_deref().unwrap_or(UNCLASSIFIED), base_amount as i64, 1);
+            this.live_records.insert(RecordViewKey::Provisional(temp_alias),
+                                     RecordViewValue::Provisional(c, t, base_amount));
         }
+        this
     }
 
+    /// Drops expenses made before the current calendar month from month stats, returning the
+    /// moment it started.
     fn keep_month(&mut self) -> OffsetDateTime {
-        let liveline = now() - MONTH_LIKE;
+        let (liveline, _) = Period::Month{back: 0}.bounds(now()).unwrap();
         
         while self.month_stats.records_alive > 0 {
             let expense_bottom_index = self.live_records.len() - self.month_stats.records_alive;
@@ -84,10 +238,9 @@ impl<U: Upstream> DbView<U> {
                 RecordViewValue::Confirmed(expense) if expense.server.time < liveline => {
                     self.month_stats.sub(&expense)
                 },
-                RecordViewValue::Provisional(client_data, time) if time < &liveline => {
+                RecordViewValue::Provisional(client_data, time, base_amount) if time < &liveline => {
                     let group = client_data.group.as_deref().unwrap_or(UNCLASSIFIED);
-                    let amount = client_data.amount as i64;
-                    self.month_stats.raw_add(group, -amount, -1);
+                    self.month_stats.raw_add(group, -(*base_amount as i64), -1);
                 },
                 _ => break,
             }
@@ -99,17 +252,81 @@ impl<U: Upstream> DbView<U> {
     fn sync_upstream(&mut self) {
         let liveline = self.keep_month();
         for msg in self.upstream.sync() {
+            if matches!(msg, ClientboundUpdate::NewSpending{..} | ClientboundUpdate::Revoked{..} |
+                             ClientboundUpdate::Amended{..} | ClientboundUpdate::InitStats{..} |
+                             ClientboundUpdate::Restored{..}) {
+                self.tagged = None;
+                self.tagged_requested = None;
+                self.period = None;
+                self.period_requested = None;
+            }
             match msg {
                 ClientboundUpdate::Revoked { expense } => {
                     self.handle_revocation(expense, liveline);
                 }
+                ClientboundUpdate::RevokeRejected { expense_id } => {
+                    self.rollback_revocation(expense_id, liveline);
+                }
+                ClientboundUpdate::Rejected { update, reason } => {
+                    match update {
+                        ServerboundUpdate::MadeExpense { temp_alias, .. } => {
+                            self.drop_provisional(temp_alias, liveline);
+                        },
+                        ServerboundUpdate::Revoked { expense_id } => {
+                            self.rollback_revocation(expense_id, liveline);
+                        },
+                        ServerboundUpdate::SetCategories { .. } => {
+                            self.categories = self.accepted_categories.clone();
+                        },
+                        ServerboundUpdate::SetBudgets { .. } => {
+                            self.budgets = self.accepted_budgets.clone();
+                        },
+                        _ => {},
+                    }
+                    self.rejection = Some(reason);
+                }
                 ClientboundUpdate::NewSpending { expense, temp_alias } => {
                     self.apply_confirmed(expense, temp_alias, liveline);
                 },
-                ClientboundUpdate::InitStats { .. } => {
-                    // this message is not meant to us; we might do sanity checks, though
+                ClientboundUpdate::Amended { previous, expense } => {
+                    self.apply_amendment(previous, expense, liveline);
+                },
+                ClientboundUpdate::Restored { expense } => {
+                    self.apply_restoration(expense, liveline);
+                },
+                ClientboundUpdate::InitStats { lifetime_stats, recent_expenses, categories } => {
+                    // Upstream reconnected; whatever we knew may have changed meanwhile.
+                    self.resync(lifetime_stats, recent_expenses, liveline);
+                    self.accepted_categories = categories.clone();
+                    self.categories = categories;
+                },
+                ClientboundUpdate::Categories { categories } => {
+                    self.accepted_categories = categories.clone();
+                    self.categories = categories;
+                },
+                ClientboundUpdate::Budgets { budgets } => {
+                    self.accepted_budgets = budgets.clone();
+                    self.budgets = budgets;
+                },
+                ClientboundUpdate::RevealTagged { tag, expenses, total } => {
+                    if self.tagged_requested.as_ref() == Some(&tag) {
+                        self.tagged_requested = None;
+                        self.tagged = Some((tag, expenses, total));
+                    }
+                },
+                ClientboundUpdate::RevealPeriod { from, to, stats, tags } => {
+                    if self.period_requested == Some((from, to)) {
+                        self.period_requested = None;
+                        self.period = Some(PeriodStats {from, to, stats: CachedStats::new(stats), tags});
+                    }
+                },
+                ClientboundUpdate::ExchangeRates { rates } => {
+                    self.exchange_rates = rates.into_iter().collect();
                 },
                 ClientboundUpdate::RevealHistory { expenses } => {
+                    self.history_requested = false;
+                    // Pages do not overlap, so an empty one is the last.
+                    self.history_exhausted = expenses.is_empty();
                     for exp in expenses {
                         self.live_records.insert(
                             RecordViewKey::Confirmed(exp.server.time, exp.server.uid),
@@ -121,7 +338,45 @@ impl<U: Upstream> DbView<U> {
         }
     }
 
+    /// Replaces everything confirmed with a fresh snapshot from upstream, keeping expenses which
+    /// are still waiting for confirmation.
+    fn resync(&mut self, lifetime_stats: GroupTotals, recent_expenses: Vec<Expense>,
+              liveline: OffsetDateTime) {
+        let provisional: Vec<_> = std::mem::replace(&mut self.live_records, LiqueMap::new())
+            .consume()
+            .filter(|(k, _)| matches!(k, RecordViewKey::Provisional(_)))
+            .collect();
+        
+        self.life_stats = CachedStats::new(lifetime_stats);
+        self.month_stats = CachedStats::default();
+        for exp in recent_expenses {
+            if exp.server.time >= liveline {
+                self.month_stats.add(&exp);
+            }
+            self.live_records.insert(
+                RecordViewKey::Confirmed(exp.server.time, exp.server.uid),
+                RecordViewValue::Confirmed(exp));
+        }
+        for (key, value) in provisional {
+            if let RecordViewValue::Provisional(c, _, base_amount) = &value {
+                let group = c.group.as_deref().unwrap_or(UNCLASSIFIED);
+                self.life_stats.raw_add(group, *base_amount as i64, 1);
+                self.month_stats.raw_add(group, *base_amount as i64, 1);
+            }
+            self.live_records.insert(key, value);
+        }
+        
+        // Upstream has either applied those or forgotten them; the snapshot tells which.
+        self.pending_revokes.clear();
+        self.history_requested = false;
+        self.history_exhausted = false;
+    }
+
     fn handle_revocation(&mut self, expense: Expense, liveline: OffsetDateTime) {
+        if self.pending_revokes.remove(&expense.server.uid).is_some() {
+            return;  // we've adjusted stats when requesting this revocation
+        }
+        
         let had_known_record = self.live_records.remove(
             &RecordViewKey::Confirmed(expense.server.time, expense.server.uid)
         );
@@ -132,22 +387,127 @@ impl<U: Upstream> DbView<U> {
         }
     }
 
-    fn apply_confirmed(&mut self, expense: Expense, temp_alias: Uuid, liveline: OffsetDateTime) {
-        assert!(!expense.client.revoked);
+    fn rollback_revocation(&mut self, expense_id: Uuid, liveline: OffsetDateTime) {
+        let Some(expense) = self.pending_revokes.remove(&expense_id) else {return};
+        
+        self.life_stats.add(&expense);
+        if expense.server.time >= liveline {
+            self.month_stats.add(&expense);
+        }
+        if self.last_revoked.as_ref().is_some_and(|e| e.server.uid == expense_id) {
+            self.last_revoked = None;
+        }
         
+        let insert_pos = RecordViewKey::Confirmed(expense.server.time, expense.server.uid);
+        self.live_records.insert(insert_pos, RecordViewValue::Confirmed(expense));
+    }
+
+    fn drop_provisional(&mut self, temp_alias: Uuid, liveline: OffsetDateTime) {
         let remove_pos = RecordViewKey::Provisional(temp_alias);
-        let was_foreign = self.live_records.remove(&remove_pos).is_none();
-        if was_foreign {
-            self.life_stats.add(&expense);
-            if expense.server.time >= liveline {
-                self.month_stats.add(&expense);
-            }
+        let Some(RecordViewValue::Provisional(c, time, estimate)) = self.live_records.remove(&remove_pos) else {
+            return
+        };
+        let group = c.group.as_deref().unwrap_or(UNCLASSIFIED);
+        self.life_stats.raw_add(group, -(estimate as i64), -1);
+        if time >= liveline {
+            self.month_stats.raw_add(group, -(estimate as i64), -1);
         }
+    }
 
+    fn apply_confirmed(&mut self, expense: Expense, temp_alias: Uuid, liveline: OffsetDateTime) {
         let insert_pos = RecordViewKey::Confirmed(expense.server.time, expense.server.uid);
+        // A resubmitted expense is confirmed as stored earlier, even if it got revoked since.
+        let known = expense.client.revoked || self.live_records.contains_key(&insert_pos);
+        let remove_pos = RecordViewKey::Provisional(temp_alias);
+        match self.live_records.remove(&remove_pos) {
+            // Confirmation replayed after reconnecting, while the snapshot already had it.
+            None if known => return,
+            Some(RecordViewValue::Provisional(c, time, estimate)) if known => {
+                let group = c.group.as_deref().unwrap_or(UNCLASSIFIED);
+                self.life_stats.raw_add(group, -(estimate as i64), -1);
+                if time >= liveline {
+                    self.month_stats.raw_add(group, -(estimate as i64), -1);
+                }
+                return;
+            },
+            None => {
+                self.life_stats.add(&expense);
+                if expense.server.time >= liveline {
+                    self.month_stats.add(&expense);
+                }
+            },
+            Some(RecordViewValue::Provisional(_, _, estimate)) => {
+                // Upstream might have converted currency at another rate than we did.
+                let group = expense.client.group.as_deref().unwrap_or(UNCLASSIFIED);
+                let correction = expense.server.base_amount as i64 - estimate as i64;
+                self.life_stats.raw_add(group, correction, 0);
+                if expense.server.time >= liveline {
+                    self.month_stats.raw_add(group, correction, 0);
+                }
+            },
+            Some(RecordViewValue::Confirmed(_)) => unreachable!("provisional key holds confirmed record"),
+        }
+
         self.live_records.insert(insert_pos, RecordViewValue::Confirmed(expense));
     }
 
+    fn apply_amendment(&mut self, previous: Expense, expense: Expense, liveline: OffsetDateTime) {
+        let all_loaded = self.live_records.len() >= self.life_stats.records_alive;
+        self.live_records.remove(&RecordViewKey::Confirmed(previous.server.time, previous.server.uid));
+        
+        self.life_stats.sub(&previous);
+        if previous.server.time >= liveline {
+            self.month_stats.sub(&previous);
+        }
+        self.life_stats.add(&expense);
+        if expense.server.time >= liveline {
+            self.month_stats.add(&expense);
+        }
+        
+        // Records must stay adjacent to each other; one moved past the oldest known record will
+        // be revealed in its new place when history there is loaded.
+        if all_loaded || self.within_loaded(&expense) || expense.server.time >= liveline {
+            let insert_pos = RecordViewKey::Confirmed(expense.server.time, expense.server.uid);
+            self.live_records.insert(insert_pos, RecordViewValue::Confirmed(expense));
+        }
+    }
+
+    fn apply_restoration(&mut self, expense: Expense, liveline: OffsetDateTime) {
+        let insert_pos = RecordViewKey::Confirmed(expense.server.time, expense.server.uid);
+        if self.live_records.contains_key(&insert_pos) {
+            return;
+        }
+        if self.last_revoked.as_ref().is_some_and(|e| e.server.uid == expense.server.uid) {
+            self.last_revoked = None;
+        }
+        
+        let all_loaded = self.live_records.len() >= self.life_stats.records_alive;
+        self.life_stats.add(&expense);
+        if expense.server.time >= liveline {
+            self.month_stats.add(&expense);
+        }
+        // Same as for amended ones.
+        if all_loaded || self.within_loaded(&expense) || expense.server.time >= liveline {
+            self.live_records.insert(insert_pos, RecordViewValue::Confirmed(expense));
+        }
+    }
+
+    /// Whether the expense is no older than the oldest known record.
+    fn within_loaded(&self, expense: &Expense) -> bool {
+        match self.live_records.get_index(0) {
+            Some((RecordViewKey::Confirmed(time, uid), _)) =>
+                (*time, *uid) <= (expense.server.time, expense.server.uid),
+            _ => false,
+        }
+    }
+
+    /// Changes amount, category and time of a confirmed expense. Stats are corrected once
+    /// upstream confirms the amendment.
+    pub fn amend_expense(&mut self, expense_id: Uuid, info: ClientData, time: OffsetDateTime) {
+        assert!(!info.revoked);
+        self.upstream.submit(ServerboundUpdate::Amend{expense_id, info, time});
+    }
+
     pub fn month_transactions_info(&mut self) -> (u64, usize) {
         self.sync_upstream();
         (self.month_stats.total_spending, self.month_stats.records_alive)
@@ -162,18 +522,109 @@ impl<U: Upstream> DbView<U> {
         self.life_stats.records_alive
     }
 
-    pub fn month_pie(&mut self) -> &[(String, u64)] {
+    /// Month spendings by tag, largest first, with counts; expenses with several tags count for each.
+    pub fn month_tags(&mut self) -> Vec<(String, u64, usize)> {
+        self.sync_upstream();
+        let month_start = self.live_records.len().saturating_sub(self.month_stats.records_alive);
+        let mut totals: BTreeMap<String, (u64, usize)> = BTreeMap::new();
+        for (_, record) in self.live_records.range_mut_idx(month_start..) {
+            let (tags, base_amount) = match record {
+                RecordViewValue::Confirmed(e) => (&e.client.tags, e.server.base_amount),
+                RecordViewValue::Provisional(c, _, base_amount) => (&c.tags, *base_amount),
+            };
+            for tag in tags {
+                let total = totals.entry(tag.clone()).or_default();
+                total.0 += base_amount;
+                total.1 += 1;
+            }
+        }
+        let mut totals: Vec<_> = totals.into_iter().map(|(tag, (total, count))| (tag, total, count)).collect();
+        totals.sort_by_key(|t| std::cmp::Reverse(t.1));
+        totals
+    }
+
+    /// Stats of `period`, upstream's ones if it is not the current month; `None` while upstream is
+    /// asked for them.
+    fn period_stats(&mut self, period: Period) -> Option<&CachedStats> {
+        self.sync_upstream();
+        if period == (Period::Month{back: 0}) {
+            return Some(&self.month_stats);
+        }
+        self.asked_period(period).map(|p| &p.stats)
+    }
+    
+    fn asked_period(&mut self, period: Period) -> Option<&PeriodStats> {
+        let bounds = period.bounds(now()).map_or((None, None), |(from, to)| (Some(from), Some(to)));
+        match &self.period {
+            Some(p) if (p.from, p.to) == bounds => {},
+            _ => {
+                if self.period_requested != Some(bounds) {
+                    self.period_requested = Some(bounds);
+                    self.upstream.submit(ServerboundUpdate::QueryPeriod{from: bounds.0, to: bounds.1});
+                }
+                return None;
+            },
+        }
+        self.period.as_ref()
+    }
+    
+    /// Spendings and their count over `period`; `None` while upstream is asked for them.
+    pub fn period_transactions_info(&mut self, period: Period) -> Option<(u64, usize)> {
+        self.period_stats(period).map(|s| (s.total_spending, s.records_alive))
+    }
+    
+    /// Spendings over `period` by subcategories of `parent`; see `CachedStats::rolled_up`.
+    pub fn period_pie(&mut self, period: Period, parent: Option<&str>) -> Option<Vec<(String, u64, bool)>> {
+        self.period_stats(period).map(|s| s.rolled_up(parent))
+    }
+    
+    /// Spendings over `period` by tag, like `month_tags`.
+    pub fn period_tags(&mut self, period: Period) -> Option<Vec<(String, u64, usize)>> {
+        if period == (Period::Month{back: 0}) {
+            return Some(self.month_tags());
+        }
         self.sync_upstream();
-        self.month_stats.group_spendings.as_slice()
+        self.asked_period(period).map(|p| p.tags.clone())
     }
 
-    pub fn life_pie(&mut self) -> &[(String, u64)] {
+    /// Most recent expenses with `tag`, oldest first, and totals of all such expenses; `None` while
+    /// upstream is asked for them.
+    pub fn tagged(&mut self, tag: &str) -> Option<(&[Expense], (u64, usize))> {
         self.sync_upstream();
-        self.life_stats.group_spendings.as_slice()
+        match &self.tagged {
+            Some((t, _, _)) if t == tag => {},
+            _ => {
+                if self.tagged_requested.as_deref() != Some(tag) {
+                    self.tagged_requested = Some(tag.to_owned());
+                    self.upstream.submit(ServerboundUpdate::QueryTagged{
+                        tag: tag.to_owned(), before: now(), amount: TAGGED_SHOWN});
+                }
+                return None;
+            },
+        }
+        self.tagged.as_ref().map(|(_, expenses, total)| (expenses.as_slice(), *total))
+    }
+
+    /// Asks upstream for older expenses if fewer than `n` recentmost ones are loaded.
+    fn request_history(&mut self, n: usize) {
+        let have_records = self.live_records.len();
+        if self.history_requested || self.history_exhausted || n <= have_records ||
+                have_records >= self.life_stats.records_alive {
+            return;
+        }
+        
+        let before = match self.live_records.get_index(0) {
+            Some((RecordViewKey::Confirmed(time, uid), _)) => (*time, *uid),
+            _ => (now(), Uuid::max()),
+        };
+        let amount = n - have_records + HISTORY_PREFETCH;
+        self.upstream.submit(ServerboundUpdate::QueryHistory{before, amount});
+        self.history_requested = true;
     }
 
     pub fn load_last_spendings(&mut self, n: usize) -> impl Iterator<Item = MayLoad<'_>> {
         self.sync_upstream();
+        self.request_history(n);
         
         // iterators are unfortunately not reversible yet
         // TODO: fix liquemap crate
@@ -193,6 +644,7 @@ impl<U: Upstream> DbView<U> {
 
     pub fn load_some_spendings(&mut self, rev_from: usize, rev_to: usize) -> impl Iterator<Item = MayLoad<'_>> {
         self.sync_upstream();
+        self.request_history(rev_to);
         
         let total_records = self.life_stats.records_alive;
         let have_records = self.live_records.len();
@@ -207,8 +659,12 @@ impl<U: Upstream> DbView<U> {
         visible.chain(missing).take(rev_to - rev_from)
     }
 
-    pub fn insert_expense(&mut self, c: ClientData) {
+    /// Records an expense, returning budgets which it has made overspent.
+    pub fn insert_expense(&mut self, c: ClientData) -> Vec<Budget> {
         assert!(!c.revoked);
+        // Undo is offered for the latest action only.
+        self.last_revoked = None;
+        let within_before: Vec<bool> = self.spent_on_budgets().iter().map(|u| u.remaining() >= 0).collect();
         
         let t = now();
         // let temp_alias = Uuid::now_v7();  <- no time facilities on WASM
@@ -220,14 +676,131 @@ impl<U: Upstream> DbView<U> {
         );
         let temp_alias = Uuid::new_v7(timestamp);
         
-        self.life_stats.raw_add(c.group.as_deref().unwrap_or(UNCLASSIFIED), c.amount as i64, 1);
-        self.month_stats.raw_add(c.group.as_deref().unwrap_or(UNCLASSIFIED), c.amount as i64, 1);
+        let base_amount = self.estimate_base(&c);
+        self.life_stats.raw_add(c.group.as_deref().unwrap_or(UNCLASSIFIED), base_amount as i64, 1);
+        self.month_stats.raw_add(c.group.as_deref().unwrap_or(UNCLASSIFIED), base_amount as i64, 1);
         
-        self.live_records.insert(RecordViewKey::Provisional(temp_alias), RecordViewValue::Provisional(c.clone(), t));
+        self.live_records.insert(RecordViewKey::Provisional(temp_alias),
+                                 RecordViewValue::Provisional(c.clone(), t, base_amount));
         self.upstream.submit(ServerboundUpdate::MadeExpense {
             info: c,
             temp_alias,
+            time: t,
         });
+        
+        self.spent_on_budgets().into_iter().zip(within_before)
+            .filter(|(u, within)| *within && u.remaining() < 0)
+            .map(|(u, _)| u.budget)
+            .collect()
+    }
+
+    /// Revokes a confirmed expense, adjusting stats right away; they are restored if upstream
+    /// rejects the revocation.
+    pub fn revoke_expense(&mut self, time: OffsetDateTime, expense_id: Uuid) {
+        let liveline = self.keep_month();
+        
+        let key = RecordViewKey::Confirmed(time, expense_id);
+        let Some(RecordViewValue::Confirmed(expense)) = self.live_records.remove(&key) else {
+            // Found by tag beyond loaded history; stats follow once upstream reports the revocation.
+            self.last_revoked = None;
+            self.upstream.submit(ServerboundUpdate::Revoked{expense_id});
+            return;
+        };
+        
+        self.life_stats.sub(&expense);
+        if expense.server.time >= liveline {
+            self.month_stats.sub(&expense);
+        }
+        
+        self.upstream.submit(ServerboundUpdate::Revoked{expense_id});
+        self.last_revoked = Some(expense.clone());
+        self.pending_revokes.insert(expense_id, expense);
+    }
+
+    fn estimate_base(&self, c: &ClientData) -> u64 {
+        if c.currency == BASE_CURRENCY {
+            return c.amount;
+        }
+        // Expenses left from earlier runs may come before upstream tells the rates.
+        let Some(rate) = self.exchange_rates.get(&c.currency) else {return 0};
+        to_base(c.amount, &c.currency, *rate)
+    }
+
+    /// Currencies expenses may be recorded in, base one first.
+    pub fn currencies(&mut self) -> Vec<String> {
+        self.sync_upstream();
+        std::iter::once(BASE_CURRENCY.to_owned())
+            .chain(self.exchange_rates.keys().cloned())
+            .collect()
+    }
+
+    pub fn exchange_rates(&mut self) -> &BTreeMap<String, f64> {
+        self.sync_upstream();
+        &self.exchange_rates
     }
-}
 
+    /// Asks upstream to save all expenses; see `Upstream::export`.
+    pub fn export(&mut self, format: ExportFormat, include_revoked: bool)
+            -> tokio::sync::oneshot::Receiver<Result<String, String>> {
+        self.upstream.export(format, include_revoked)
+    }
+
+    /// Changes the rate for expenses recorded from now on; existing ones keep their conversion.
+    pub fn set_exchange_rate(&mut self, currency: String, base_per_unit: f64) {
+        assert!(is_currency_code(&currency) && currency != BASE_CURRENCY);
+        assert!(base_per_unit.is_finite() && base_per_unit > 0.0);
+        self.upstream.submit(ServerboundUpdate::SetExchangeRate{currency, base_per_unit});
+    }
+    
+    /// Categories offered for new expenses, in order; empty until upstream tells them.
+    pub fn categories(&mut self) -> &[Category] {
+        self.sync_upstream();
+        &self.categories
+    }
+    
+    /// Replaces the whole list of categories; expenses recorded earlier keep their groups.
+    pub fn set_categories(&mut self, categories: Vec<Category>) {
+        assert!(categories_valid(&categories));
+        self.categories = categories.clone();
+        self.upstream.submit(ServerboundUpdate::SetCategories{categories});
+    }
+    
+    /// Monthly budgets, overall one first, with spendings against them over the month.
+    pub fn budget_uses(&mut self) -> Vec<BudgetUse> {
+        self.sync_upstream();
+        self.spent_on_budgets()
+    }
+    
+    fn spent_on_budgets(&self) -> Vec<BudgetUse> {
+        self.budgets.iter()
+            .map(|b| BudgetUse {budget: b.clone(), spent: self.month_stats.spent_within(b.category.as_deref())})
+            .collect()
+    }
+    
+    /// Replaces the whole list of monthly budgets.
+    pub fn set_budgets(&mut self, budgets: Vec<Budget>) {
+        assert!(budgets_valid(&budgets));
+        self.budgets = budgets.clone();
+        self.upstream.submit(ServerboundUpdate::SetBudgets{budgets});
+    }
+    
+    pub fn rejection(&self) -> Option<&str> {
+        self.rejection.as_deref()
+    }
+
+    pub fn last_revoked(&self) -> Option<&Expense> {
+        self.last_revoked.as_ref()
+    }
+
+    /// Undoes the latest revocation; the expense shows up again once upstream restores it.
+    pub fn restore_last_revoked(&mut self) {
+        if let Some(expense) = self.last_revoked.take() {
+            self.upstream.submit(ServerboundUpdate::Restore{expense_id: expense.server.uid});
+        }
+    }
+
+    /// Call once undoing the latest revocation is no longer offered.
+    pub fn forget_last_revoked(&mut self) {
+        self.last_revoked = None;
+    }
+}
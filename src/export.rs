@@ -0,0 +1,239 @@
+// #[sides(server, client#selfhost)]
+
+use time::format_description::well_known::Rfc3339;
+use time::{format_description, UtcOffset};
+use serde::Serialize;
+
+use crate::crosstyping::*;
+
+
+/// How one expense looks in an export or the JSON API: amounts are decimal, as people write them.
+#[derive(Serialize)]
+pub struct ExportRow<'a> {
+    id: String,
+    time: String,
+    amount: String,
+    currency: &'a str,
+    amount_base: String,
+    group: Option<&'a str>,
+    note: Option<&'a str>,
+    tags: &'a [String],
+    revoked: bool,
+    principal: Option<&'a str>,
+}
+impl<'a> ExportRow<'a> {
+    pub fn new(e: &'a Expense) -> Self {
+        Self {
+            id:          e.server.uid.to_string(),
+            time:        e.server.time.format(&Rfc3339).unwrap(),
+            amount:      format_amount(e.client.amount, &e.client.currency),
+            currency:    &e.client.currency,
+            amount_base: format_amount(e.server.base_amount, BASE_CURRENCY),
+            group:       e.client.group.as_deref(),
+            note:        e.client.note.as_deref(),
+            tags:        &e.client.tags,
+            revoked:     e.client.revoked,
+            principal:   e.server.principal.as_deref(),
+        }
+    }
+}
+
+const CSV_HEADER: &str = "id,time,amount,currency,amount_base,group,note,tags,revoked,principal\n";
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_owned()
+    }
+}
+
+/// Account the expenses are paid from in a ledger journal, unless told otherwise.
+pub const DEFAULT_FUNDING_ACCOUNT: &str = "assets:cash";
+
+/// Reads a UTC offset like `+03:00`, `-0530` or `Z`. The sign may be left out of positive ones,
+/// since `+` means space in query strings.
+pub fn parse_utc_offset(text: &str) -> Option<UtcOffset> {
+    let text = text.trim();
+    if text == "Z" {
+        return Some(UtcOffset::UTC);
+    }
+    let (sign, rest) = match text.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest.get(..2)?, rest.get(2..)?));
+    let minutes = if minutes.is_empty() {0} else {minutes.parse::<i8>().ok()?};
+    UtcOffset::from_hms(sign * hours.parse::<i8>().ok()?, sign * minutes, 0).ok()
+}
+
+/// Ledger descriptions and account names end at `;` and at double spaces, and span one line.
+fn ledger_text(text: &str) -> String {
+    text.replace(';', ",").split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// One journal entry, paying for the expense from `funding_account` on its local date. Amounts in
+/// other currencies carry their cost in base currency, tags become posting tags. Revoked expenses
+/// are commented out.
+fn ledger_entry(e: &Expense, funding_account: &str, offset: UtcOffset) -> String {
+    let group = e.client.group.as_deref().unwrap_or(UNCLASSIFIED);
+    let description = e.client.note.as_deref().map(ledger_text).filter(|d| !d.is_empty())
+        .unwrap_or_else(|| ledger_text(group));
+    let mut amount = format!("{} {}", format_amount(e.client.amount, &e.client.currency), e.client.currency);
+    if e.client.currency != BASE_CURRENCY {
+        amount += &format!(" @@ {} {BASE_CURRENCY}", format_amount(e.server.base_amount, BASE_CURRENCY));
+    }
+    
+    // Subcategories become subaccounts, so colons within names must not split them further.
+    let account = ledger_text(group).replace(':', "-").replace(SUBCATEGORY_SEPARATOR, ":");
+    
+    let mut entry = vec![
+        format!("{} * {description}  ; id:{}", e.server.time.to_offset(offset).date(), e.server.uid),
+        format!("    {:<38}  {amount}", format!("expenses:{account}")),
+    ];
+    // One per line, as both ledger and hledger read a tag's value up to the end of line.
+    entry.extend(e.client.tags.iter().map(|tag| format!("    ; {}:", ledger_text(tag).replace(':', "-"))));
+    entry.push(format!("    {funding_account}"));
+    let prefix = if e.client.revoked {"; "} else {""};
+    entry.iter().map(|line| format!("{prefix}{line}\n")).collect::<String>() + "\n"
+}
+
+/// Renders expenses page by page, so that the whole history needs not be kept in memory.
+pub struct Exporter {
+    format: ExportFormat,
+    rows_written: usize,
+    funding_account: String,
+    offset: UtcOffset,
+}
+impl Exporter {
+    pub fn new(format: ExportFormat) -> Self {
+        Self {format, rows_written: 0, funding_account: DEFAULT_FUNDING_ACCOUNT.to_owned(), offset: UtcOffset::UTC}
+    }
+    
+    /// Sets the account ledger entries are paid from, and the timezone their dates are in.
+    pub fn with_ledger(mut self, funding_account: &str, offset: UtcOffset) -> Self {
+        self.funding_account = ledger_text(funding_account);
+        self.offset = offset;
+        self
+    }
+    
+    pub fn begin(&self) -> String {
+        match self.format {
+            ExportFormat::Csv    => CSV_HEADER.to_owned(),
+            ExportFormat::Json   => "[".to_owned(),
+            ExportFormat::Ledger => {
+                let offset = format_description::parse("[offset_hour sign:mandatory]:[offset_minute]").unwrap();
+                format!("; Expenses paid from {}, dated at UTC{}\n\n",
+                        self.funding_account, self.offset.format(&offset).unwrap())
+            },
+        }
+    }
+    
+    pub fn page(&mut self, expenses: &[Expense]) -> String {
+        let mut out = String::new();
+        for e in expenses {
+            let row = ExportRow::new(e);
+            match self.format {
+                ExportFormat::Ledger => out += &ledger_entry(e, &self.funding_account, self.offset),
+                ExportFormat::Csv => {
+                    out += &[row.id.as_str(), &row.time, &row.amount, row.currency, &row.amount_base,
+                             &csv_field(row.group.unwrap_or("")), &csv_field(row.note.unwrap_or("")),
+                             &csv_field(&row.tags.join(" ")), if row.revoked {"true"} else {"false"},
+                             &csv_field(row.principal.unwrap_or(""))]
+                        .join(",");
+                    out.push('\n');
+                },
+                ExportFormat::Json => {
+                    if self.rows_written > 0 {
+                        out.push(',');
+                    }
+                    out += "\n  ";
+                    out += &serde_json::to_string(&row).unwrap();
+                },
+            }
+            self.rows_written += 1;
+        }
+        out
+    }
+    
+    pub fn end(&self) -> String {
+        match self.format {
+            ExportFormat::Csv | ExportFormat::Ledger => String::new(),
+            ExportFormat::Json => "\n]\n".to_owned(),
+        }
+    }
+}
+
+
+// ---------------------------------------------------------------------------------------------- //
+// Self-hosted side, exporting the local database from the command line.
+
+#[cfg(feature = "selfhost")]
+pub use client::*;
+
+#[cfg(feature = "selfhost")]
+mod client {
+    use anyhow::{bail, Context, Result};
+    use time::UtcOffset;
+    use std::path::PathBuf;
+    
+    use super::{parse_utc_offset, Exporter, DEFAULT_FUNDING_ACCOUNT};
+    use crate::selfhost::SingleUserSqlite;
+    use crate::crosstyping::*;
+
+
+    const USAGE: &str = "\
+Usage: ting-expense-a export [OUTPUT] [--format csv|json|ledger] [--funding ACCOUNT] [--tz OFFSET]
+                             [--revoked] [--database FILE]
+
+Writes all expenses from the local database, oldest first, to OUTPUT or to standard output.
+  --format FORMAT    csv (default), json, or ledger for a ledger/hledger journal
+  --funding ACCOUNT  ledger account expenses are paid from, assets:cash by default
+  --tz OFFSET        UTC offset like +03:00 for dates of ledger entries, local one by default
+  --revoked          include revoked expenses (commented out in ledger journal)";
+    
+    /// Runs `ting-expense-a export ...` command line.
+    pub fn run_command(args: &[String]) -> Result<()> {
+        // Asked before anything else, as the offset cannot be told once other threads run.
+        let mut offset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
+        let (mut output, mut format) = (None, ExportFormat::Csv);
+        let mut funding = DEFAULT_FUNDING_ACCOUNT.to_owned();
+        let (mut include_revoked, mut database) = (false, None);
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" | "-h" => {
+                    println!("{USAGE}");
+                    return Ok(());
+                },
+                "--format" => format = match args.next().context(USAGE)?.as_str() {
+                    "csv"    => ExportFormat::Csv,
+                    "json"   => ExportFormat::Json,
+                    "ledger" => ExportFormat::Ledger,
+                    name => bail!("unknown format {name}\n\n{USAGE}"),
+                },
+                "--funding" => funding = args.next().context(USAGE)?.clone(),
+                "--tz" => {
+                    let text = args.next().context(USAGE)?;
+                    offset = parse_utc_offset(text).with_context(|| format!("{text} is not a UTC offset"))?;
+                },
+                "--revoked" => include_revoked = true,
+                "--database" => database = Some(PathBuf::from(args.next().context(USAGE)?)),
+                flag if flag.starts_with("--") => bail!("unknown flag {flag}\n\n{USAGE}"),
+                _ if output.is_none() => output = Some(PathBuf::from(arg)),
+                _ => bail!("{USAGE}"),
+            }
+        }
+        let path = database.or_else(crate::selfhost::default_path)
+            .context("no storage directory for the database")?;
+        
+        let exporter = Exporter::new(format).with_ledger(&funding, offset);
+        let text = SingleUserSqlite::open(&path)?.export_with(exporter, include_revoked)?;
+        match output {
+            Some(output) => std::fs::write(&output, text)
+                .with_context(|| format!("cannot write {}", output.display()))?,
+            None => print!("{text}"),
+        }
+        Ok(())
+    }
+}
@@ -6,61 +6,382 @@ use eframe::{App, CreationContext};
 use std::collections::BTreeMap;
 use std::sync::Arc;
 
-use crate::crosstyping::{ClientData, Upstream};
+use time::{format_description, OffsetDateTime, PrimitiveDateTime};
+use uuid::Uuid;
+
+use crate::crosstyping::{ClientData, Expense, ExportFormat, Upstream, UNCLASSIFIED};
+use crate::crosstyping::{categories_valid, Category, MAX_CATEGORIES};
+use crate::crosstyping::{budgets_valid, Budget};
+use crate::crosstyping::{normalize_category, parent_category, SUBCATEGORY_SEPARATOR};
+use crate::crosstyping::parse_tags;
+use crate::crosstyping::{currency_sign, is_currency_code, BASE_CURRENCY};
+use crate::crosstyping::{currency_exponent, format_amount, parse_amount};
+use crate::db_slice::{assume_local, local_offset_at, now, BudgetUse, MayLoad, Period};
 use crate::widgets::*;
+#[cfg(all(feature = "graphics_nowasm", not(feature = "selfhost")))]
+use crate::remotehost::RemoteDatabase;
+
 
+/// Names app's storage directory, where settings, the keystore and the outbox are kept.
+pub const APP_ID: &str = "ton.ting.ExpenseExplorer";
+
+/// Where exported expenses are saved: Downloads folder if there is one, otherwise home folder
+/// or the current one.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn export_path(format: ExportFormat) -> std::path::PathBuf {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))
+        .map(std::path::PathBuf::from);
+    let dir = home.as_ref().map(|home| home.join("Downloads")).filter(|dir| dir.is_dir())
+        .or(home).unwrap_or_default();
+    dir.join(format!("expenses-{}.{}", now().date(), format.extension()))
+}
 
-const CATEGORIES: [(&'static str, Color32, Option<&'static str>); 5] = [
-    ("🍞", Color32::GREEN,     Some("еду")),
-    ("🏡", Color32::DARK_GRAY, Some("хозтовары")),
-    ("🚋", Color32::ORANGE,    Some("транспорт")),
-    ("всё", Color32::GOLD,     None),
-    ("📝", Color32::BLACK,     None),
-];
-fn color_cat(a: &str) -> Color32 {
-    match a {
-        "еду"       => Color32::GREEN,
-        "хозтовары" => Color32::DARK_GRAY,
-        "транспорт" => Color32::ORANGE,
-        _           => Color32::GOLD,
+/// Slider options: user's categories, then unclassified and write-in ones.
+fn category_options(categories: &[Category]) -> Vec<(&str, Color32, Option<&str>)> {
+    categories.iter()
+        .map(|c| (c.icon.as_str(), Color32::from_rgb(c.color[0], c.color[1], c.color[2]), Some(c.name.as_str())))
+        .chain([("всё", Color32::GOLD, None), ("📝", Color32::BLACK, None)])
+        .collect()
+}
+fn color_cat(categories: &[Category], a: &str) -> Color32 {
+    // Subcategories without a color of their own take the closest parent's one.
+    let mut path = Some(a);
+    while let Some(p) = path {
+        if let Some(c) = categories.iter().find(|c| c.name == p) {
+            return Color32::from_rgb(c.color[0], c.color[1], c.color[2]);
+        }
+        path = parent_category(p);
     }
+    Color32::GOLD
 }
 
 
 struct MainForm {
     spent: u64,
+    currency: String,
     comment: String,
+    tags: String,
     anim_category: f32,
     chosen_category: usize,
     spec_category: String,
+    // Names of categories on the slider, so that the choice is reset once they change.
+    offered: Vec<String>,
+    // Budgets the last recorded expense has made overspent, until dismissed.
+    overspent: Vec<Budget>,
 }
 impl MainForm {
     fn to_default(&mut self) {
+        // Currency is kept, as subsequent expenses are likely made in the same one.
         self.spent = 0;
         self.comment.clear();
-        self.anim_category = 3.0;
-        self.chosen_category = 3;
+        self.tags.clear();
+        self.anim_category = self.offered.len() as f32;
+        self.chosen_category = self.offered.len();
         self.spec_category.clear();
     }
+    
+    fn offer(&mut self, categories: &[Category]) {
+        if !self.offered.iter().eq(categories.iter().map(|c| &c.name)) {
+            self.offered = categories.iter().map(|c| c.name.clone()).collect();
+            self.anim_category = self.offered.len() as f32;
+            self.chosen_category = self.offered.len();
+        }
+    }
 }
 impl Default for MainForm {
     fn default() -> Self {
         MainForm {
             spent: 0,
+            currency: BASE_CURRENCY.to_owned(),
             comment: String::with_capacity(24),
-            anim_category: 3.0,
-            chosen_category: 3,
+            tags: String::new(),
+            anim_category: 0.0,
+            chosen_category: 0,
             spec_category: String::with_capacity(12),
+            offered: vec![],
+            overspent: vec![],
         }
     }
 }
 
 
+/// Time as shown and typed in forms, in local timezone.
+const FORM_TIME: &str = "[year]-[month]-[day] [hour]:[minute]";
+
+struct AmendForm {
+    expense_id: Uuid,
+    spent: u64,
+    currency: String,
+    category: String,
+    note: String,
+    tags: String,
+    time: String,
+    // Kept as is unless `time` is edited, since the form drops seconds.
+    original_time: OffsetDateTime,
+}
+impl AmendForm {
+    fn new(e: Expense) -> Self {
+        AmendForm {
+            expense_id: e.server.uid,
+            spent: e.client.amount,
+            currency: e.client.currency,
+            category: e.client.group.unwrap_or_default(),
+            note: e.client.note.unwrap_or_default(),
+            tags: e.client.tags.join(", "),
+            time: Self::shown_time(e.server.time),
+            original_time: e.server.time,
+        }
+    }
+    fn shown_time(time: OffsetDateTime) -> String {
+        let format = format_description::parse(FORM_TIME).unwrap();
+        time.to_offset(local_offset_at(time)).format(&format).unwrap()
+    }
+    fn parsed_time(&self) -> Option<OffsetDateTime> {
+        if self.time.trim() == Self::shown_time(self.original_time) {
+            return Some(self.original_time);
+        }
+        let format = format_description::parse(FORM_TIME).unwrap();
+        Some(assume_local(PrimitiveDateTime::parse(self.time.trim(), &format).ok()?))
+    }
+}
+
+
+/// Date as typed for custom periods.
+const FORM_DATE: &str = "[year]-[month]-[day]";
+
+const MONTH_NAMES: [&str; 12] = ["январь", "февраль", "март", "апрель", "май", "июнь", "июль", "август",
+                                 "сентябрь", "октябрь", "ноябрь", "декабрь"];
+
+/// Names a period for the selector, like "октябрь 2026" or "12.10.2026 — 18.10.2026".
+fn period_label(period: Period) -> String {
+    let Some((from, to)) = period.bounds(now()) else {return "всё время".to_owned()};
+    let last = to.date().previous_day().unwrap();
+    match period {
+        Period::Month{..} => format!("{} {}", MONTH_NAMES[from.month() as usize - 1], from.year()),
+        _ => {
+            let format = format_description::parse("[day].[month].[year]").unwrap();
+            format!("{} — {}", from.date().format(&format).unwrap(), last.format(&format).unwrap())
+        },
+    }
+}
+
+/// Export started from the stats screen, and how the last one went.
+#[derive(Default)]
+struct StatsForm {
+    period: Period,
+    // Bounds of a custom period as typed, applied once both are valid.
+    first: String,
+    last: String,
+    // Category whose subcategories the pie shows, top level if `None`.
+    drilled: Option<String>,
+    // Only expenses with this tag are listed, if chosen.
+    tag: Option<String>,
+    include_revoked: bool,
+    export: Option<tokio::sync::oneshot::Receiver<Result<String, String>>>,
+    exported: Option<Result<String, String>>,
+}
+
+
+/// Categories being edited, saved all at once.
+struct CategoriesForm {
+    categories: Vec<Category>,
+}
+impl CategoriesForm {
+    fn new(categories: &[Category]) -> Self {
+        CategoriesForm {categories: categories.to_vec()}
+    }
+    
+    /// What would be saved, if valid.
+    fn cleaned(&self) -> Vec<Category> {
+        self.categories.iter()
+            .map(|c| Category {name: normalize_category(&c.name), icon: c.icon.trim().to_owned(), color: c.color})
+            .collect()
+    }
+}
+
+
+/// Monthly budgets being edited, saved all at once; empty category stands for all expenses.
+struct BudgetsForm {
+    budgets: Vec<(String, u64)>,
+}
+impl BudgetsForm {
+    fn new(uses: &[BudgetUse]) -> Self {
+        let budgets = uses.iter()
+            .map(|u| (u.budget.category.clone().unwrap_or_default(), u.budget.limit))
+            .collect();
+        BudgetsForm {budgets}
+    }
+    
+    /// What would be saved, if valid.
+    fn cleaned(&self) -> Vec<Budget> {
+        self.budgets.iter()
+            .map(|(category, limit)| {
+                let category = normalize_category(category);
+                Budget {category: (!category.is_empty()).then_some(category), limit: *limit}
+            })
+            .collect()
+    }
+}
+
+fn budget_name(budget: &Budget) -> &str {
+    budget.category.as_deref().unwrap_or("все расходы")
+}
+
+
+struct RatesForm {
+    currency: String,
+    base_per_unit: f64,
+}
+impl Default for RatesForm {
+    fn default() -> Self {
+        RatesForm {
+            currency: String::with_capacity(3),
+            base_per_unit: 1.0,
+        }
+    }
+}
+
+
+/// Connected database, and TOTP secret if the device was just registered.
+#[cfg(all(feature = "graphics_nowasm", not(feature = "selfhost")))]
+type SignInResult = anyhow::Result<(RemoteDatabase, Option<Vec<u8>>)>;
+
+/// Ways to prove who we are to the server.
+#[cfg(all(feature = "graphics_nowasm", not(feature = "selfhost")))]
+enum SignIn {
+    Code(String),
+    Secret(Vec<u8>),
+    Register(String),
+    Keystore,
+}
+
+/// Where and as whom to sign in; `pending` is the attempt in flight.
+#[cfg(all(feature = "graphics_nowasm", not(feature = "selfhost")))]
+struct ConnectForm {
+    url: String,
+    device: String,
+    principal: String,
+    code: String,          // or the whole device secret, in base32
+    passphrase: String,    // of the keystore
+    unlocking: bool,       // whether to sign in with stored credentials
+    error: Option<String>,
+    pending: Option<tokio::sync::oneshot::Receiver<SignInResult>>,
+    // Freshly registered device waits until user saves its TOTP secret.
+    registered: Option<(RemoteDatabase, String)>,
+}
+#[cfg(all(feature = "graphics_nowasm", not(feature = "selfhost")))]
+impl ConnectForm {
+    /// What the code field holds: a one-time code, or a device secret which can be remembered.
+    fn parsed_code(&self) -> Option<SignIn> {
+        let code = self.code.trim();
+        if code.len() == 8 && code.bytes().all(|b| b.is_ascii_digit()) {
+            return Some(SignIn::Code(code.to_owned()));
+        }
+        let secret = totp_rs::Secret::Encoded(code.to_ascii_uppercase()).to_bytes().ok()?;
+        (secret.len() >= 16).then_some(SignIn::Secret(secret))
+    }
+    
+    fn start(&mut self, how: SignIn) {
+        use crate::keystore::{self, Credentials};
+        use tokio::task::spawn_blocking;
+        
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let url = self.url.trim().trim_end_matches('/').to_owned();
+        let device = self.device.trim().to_owned();
+        let passphrase = self.passphrase.clone();
+        
+        tokio::spawn(async move {
+            let attempt = async {
+                let (db, credentials, registered) = match how {
+                    SignIn::Code(code) => {
+                        let cookie = RemoteDatabase::login(&url, &device, &code).await?;
+                        return Ok((RemoteDatabase::open(&url, &device, &cookie).await?, None));
+                    },
+                    SignIn::Keystore => {
+                        let credentials = spawn_blocking(move || keystore::load(&passphrase)).await??;
+                        return Ok((RemoteDatabase::connect(&credentials).await?, None));
+                    },
+                    SignIn::Secret(secret) => {
+                        let credentials = Credentials {url, device, secret};
+                        (RemoteDatabase::connect(&credentials).await?, credentials, false)
+                    },
+                    SignIn::Register(principal) => {
+                        let (cookie, secret) = RemoteDatabase::register(&url, &device, &principal).await?;
+                        let db = RemoteDatabase::open(&url, &device, &cookie).await?;
+                        (db, Credentials {url, device, secret}, true)
+                    },
+                };
+                
+                if !passphrase.is_empty() {
+                    let stored = credentials.clone();
+                    if let Err(e) = spawn_blocking(move || keystore::save(&stored, &passphrase)).await? {
+                        eprintln!("Credentials were not remembered: {e:#}");
+                    }
+                }
+                Ok::<_, anyhow::Error>((db, registered.then_some(credentials.secret)))
+            };
+            let _ = tx.send(attempt.await);
+        });
+        self.pending = Some(rx);
+        self.error = None;
+    }
+}
+#[cfg(all(feature = "graphics_nowasm", not(feature = "selfhost")))]
+impl Default for ConnectForm {
+    fn default() -> Self {
+        ConnectForm {
+            url: String::new(),
+            device: String::new(),
+            principal: String::new(),
+            code: String::with_capacity(8),
+            passphrase: String::new(),
+            unlocking: crate::keystore::exists(),
+            error: None,
+            pending: None,
+            registered: None,
+        }
+    }
+}
+
+
+/// Edits an amount kept in minor units of `currency`, showing and accepting decimals.
+fn amount_editor(ui: &mut Ui, minor: &mut u64, currency: &str, prefix: &str) -> Response {
+    let scale = 10_u64.pow(currency_exponent(currency));
+    let bigness = (*minor as f64 / scale as f64).ln_1p();  // 0.00 .. 11.52
+    let drag_speed = (12.0 - bigness) * scale as f64;
+    
+    ui.add(widgets::DragValue::new(minor)
+        .range(0..=100000 * scale)
+        .speed(drag_speed)
+        .prefix(prefix)
+        .suffix(currency_sign(currency))
+        .custom_formatter(|v, _| format_amount(v as u64, currency))
+        .custom_parser(|s| parse_amount(s, currency).map(|m| m as f64)))
+}
+
+/// Lets user pick one of currencies with known exchange rates.
+fn currency_picker(ui: &mut Ui, id: &str, currency: &mut String, currencies: &[String]) {
+    ComboBox::from_id_salt(id)
+        .selected_text(format!("Валюта: {currency}"))
+        .show_ui(ui, |ui| {
+            for c in currencies {
+                ui.selectable_value(currency, c.clone(),
+                                    format!("{c} {}", currency_sign(c)));
+            }
+        });
+}
+
+
 enum CurScreen {
-    Connect,
+    #[cfg(all(feature = "graphics_nowasm", not(feature = "selfhost")))]
+    Connect(Box<ConnectForm>),
     SigningIn(Box<dyn Upstream + 'static>),
     Main(MainForm),
-    Stats,
+    Stats(StatsForm),
+    Amend(AmendForm),
+    Rates(RatesForm),
+    Categories(CategoriesForm),
+    Budgets(BudgetsForm),
 }
 
 enum UiCommands {
@@ -76,7 +397,7 @@ pub struct Trac {
     screen_buf: Vec<CurScreen>,
 }
 impl Trac {
-    pub fn new(cc: &CreationContext<'_>, db: impl Upstream + 'static) -> Self {
+    fn new(cc: &CreationContext<'_>, first_screen: CurScreen) -> Self {
         cc.egui_ctx.set_theme(Theme::Light);
         
         let mut fonts = FontDefinitions::default();
@@ -105,9 +426,127 @@ impl Trac {
         
         Trac {
             db: None,
-            screen_buf: vec![CurScreen::SigningIn(Box::new(db))]
-            // screen_buf: vec![],
+            screen_buf: vec![first_screen]
+        }
+    }
+    
+    #[cfg(all(feature = "graphics_nowasm", not(feature = "selfhost")))]
+    fn draw_connect_screen(ctx: &Context, form: &mut ConnectForm) -> Option<Box<dyn Upstream + 'static>> {
+        use tokio::sync::oneshot::error::TryRecvError;
+        
+        if let Some(pending) = &mut form.pending {
+            match pending.try_recv() {
+                Ok(Ok((db, None))) => return Some(Box::new(db)),
+                Ok(Ok((db, Some(secret)))) => {
+                    let secret = totp_rs::Secret::Raw(secret).to_encoded().to_string();
+                    form.registered = Some((db, secret));
+                    form.pending = None;
+                },
+                Ok(Err(e)) => {
+                    form.error = Some(format!("{e:#}"));
+                    form.pending = None;
+                },
+                Err(TryRecvError::Closed) => {
+                    form.error = Some("Подключение прервано".to_owned());
+                    form.pending = None;
+                },
+                Err(TryRecvError::Empty) => ctx.request_repaint_after(std::time::Duration::from_millis(100)),
+            }
+        }
+        
+        let mut proceed = false;
+        CentralPanel::default()
+            .frame(Frame::side_top_panel(&ctx.style())
+                         .inner_margin(Margin::same(18)))
+            .show(ctx, |ui| {
+                ui.vertical_centered_justified(|ui| {
+                    ui.spacing_mut().interact_size.y += 12.0;
+                    ui.spacing_mut().item_spacing.y += 12.0;
+                    
+                    if let Some((_, secret)) = &form.registered {
+                        ui.heading("Устройство зарегистрировано");
+                        ui.label("Сохраните ключ в приложении-аутентификаторе (TOTP, 8 цифр, \
+                                  период 20 секунд): без него снова войти с этого устройства \
+                                  не получится.");
+                        ui.add(Label::new(RichText::new(secret).monospace()).selectable(true));
+                        let uri = format!("otpauth://totp/TEA:{}?secret={secret}&digits=8&period=20&issuer=TEA",
+                                          form.device.trim());
+                        ui.add(Label::new(RichText::new(&uri).monospace()).selectable(true));
+                        if ui.button("Скопировать ключ").clicked() {
+                            ui.ctx().copy_text(secret.clone());
+                        }
+                        proceed = ui.button("Продолжить").clicked();
+                        return;
+                    }
+                    
+                    ui.heading("Вход");
+                    let idle = form.pending.is_none();
+                    
+                    if form.unlocking {
+                        ui.label("На этом устройстве сохранён вход.");
+                        ui.add(widgets::TextEdit::singleline(&mut form.passphrase)
+                            .password(true)
+                            .hint_text("Пароль хранилища"));
+                        if ui.add_enabled(idle && !form.passphrase.is_empty(),
+                                          Button::new("Разблокировать")).clicked() {
+                            form.start(SignIn::Keystore);
+                        }
+                        if ui.add_enabled(idle, Button::new("Войти иначе")).clicked() {
+                            form.unlocking = false;
+                            form.passphrase.clear();
+                        }
+                        if ui.add_enabled(idle, Button::new("Забыть сохранённый вход")).clicked() {
+                            match crate::keystore::forget() {
+                                Ok(()) => form.unlocking = false,
+                                Err(e) => form.error = Some(format!("{e:#}")),
+                            }
+                            form.passphrase.clear();
+                        }
+                    } else {
+                        ui.add(widgets::TextEdit::singleline(&mut form.url)
+                            .hint_text("Адрес сервера, https://..."));
+                        ui.add(widgets::TextEdit::singleline(&mut form.device)
+                            .hint_text("Имя устройства"));
+                        ui.add(widgets::TextEdit::singleline(&mut form.passphrase)
+                            .password(true)
+                            .hint_text("Пароль, чтобы запомнить вход (необязательно)"));
+                        
+                        let addressed = !form.url.trim().is_empty() && !form.device.trim().is_empty();
+                        
+                        ui.separator();
+                        ui.add(widgets::TextEdit::singleline(&mut form.code)
+                            .hint_text("Код из аутентификатора или ключ устройства"));
+                        let code = form.parsed_code();
+                        if matches!(code, Some(SignIn::Code(_))) && !form.passphrase.is_empty() {
+                            ui.label("Чтобы запомнить вход, введите ключ устройства вместо кода.");
+                        }
+                        if ui.add_enabled(idle && addressed && code.is_some(), Button::new("Войти")).clicked() {
+                            form.start(code.unwrap());
+                        }
+                        
+                        ui.separator();
+                        ui.add(widgets::TextEdit::singleline(&mut form.principal)
+                            .hint_text("Имя нового пользователя"));
+                        let principal = form.principal.trim().to_owned();
+                        if ui.add_enabled(idle && addressed && principal.len() > 1,
+                                          Button::new("Зарегистрироваться")).clicked() {
+                            form.start(SignIn::Register(principal));
+                        }
+                    }
+                    
+                    if !idle {
+                        ui.spinner();
+                    }
+                    if let Some(error) = &form.error {
+                        ui.colored_label(Color32::DARK_RED, error);
+                    }
+                });
+            });
+        
+        if proceed {
+            return form.registered.take().map(|(db, _)| Box::new(db) as Box<dyn Upstream>);
         }
+        None
     }
     
     fn draw_main_screen(db: &mut DbView, ctx: &Context, form: &mut MainForm) -> Vec<UiCommands> {
@@ -118,6 +557,9 @@ impl Trac {
             .show(ctx, |ui| {
                 ui.horizontal_centered(|ui| {
                     ui.label("Обозреватель расходов TEA | Отладочная версия");
+                    if let Some(reason) = db.rejection() {
+                        ui.colored_label(Color32::DARK_RED, format!("Сервер не принял изменение: {reason}"));
+                    }
                 });
             });
         
@@ -125,22 +567,21 @@ impl Trac {
             .frame(Frame::side_top_panel(&ctx.style())
                          .inner_margin(Margin::same(18)))
             .show(ctx, |ui| {
-                let bigness = (form.spent as f32).ln_1p();  // 0.00 .. 11.52
-                let drag_speed = 12.0 - bigness;
-                
                 ui.vertical_centered_justified(|mut ui| {
                     ui.spacing_mut().interact_size.y += 12.0;
                     ui.spacing_mut().item_spacing.y += 12.0;
                     
-                    ui.add(widgets::DragValue::new(&mut form.spent)
-                        .range(0..=100000)
-                        .speed(drag_speed)
-                        .prefix("Итого: "));
+                    amount_editor(ui, &mut form.spent, &form.currency, "Итого: ");
+                    currency_picker(ui, "main_currency", &mut form.currency, &db.currencies());
                     
+                    let categories = db.categories().to_vec();
+                    form.offer(&categories);
+                    let options = category_options(&categories);
+                    let write_in = options.len() - 1;
                     expense_category_slider(&mut ui, &mut form.anim_category,
-                        &mut form.chosen_category, &CATEGORIES);
+                        &mut form.chosen_category, &options);
                     
-                    let write_in_cat = form.anim_category == 4.0;
+                    let write_in_cat = form.anim_category == write_in as f32;
                     CollapsingHeader::new("Другая категория")
                         .open(Some(write_in_cat))
                         .show(ui, |ui| {
@@ -150,9 +591,11 @@ impl Trac {
                     ui.add(widgets::TextEdit::multiline(&mut form.comment)
                         .desired_rows(2)
                         .hint_text("Комментарий"));
+                    ui.add(widgets::TextEdit::singleline(&mut form.tags)
+                        .hint_text("Метки через запятую: отпуск-2026, подарок"));
                     
                     if form.spent == 0 {ui.disable();}
-                    if write_in_cat && form.spec_category.is_empty() {
+                    if write_in_cat && normalize_category(&form.spec_category).is_empty() {
                         ui.disable();
                     }
                     
@@ -160,16 +603,20 @@ impl Trac {
                                          .strong().color(Color32::DARK_BLUE);
                     let spent = Button::new(spent).fill(Color32::LIGHT_BLUE);
                     if ui.add(spent).clicked() {
-                        let c = if form.chosen_category == 4 {
-                            Some(std::mem::take(&mut form.spec_category).into())
+                        let c = if form.chosen_category == write_in {
+                            Some(normalize_category(&std::mem::take(&mut form.spec_category)))
                         } else {
-                            CATEGORIES[form.chosen_category].2.map(|s| s.into())
+                            options[form.chosen_category].2.map(|s| s.into())
                         };
                         
-                        db.insert_expense(ClientData{
+                        let note = form.comment.trim();
+                        form.overspent = db.insert_expense(ClientData{
                             amount: form.spent,
                             group: c,
                             revoked: false,
+                            note: (!note.is_empty()).then(|| note.to_owned()),
+                            currency: form.currency.clone(),
+                            tags: parse_tags(&form.tags),
                         });
                         form.to_default();
                     }
@@ -183,26 +630,81 @@ impl Trac {
             .show(ctx, |ui| {
                 ui.vertical_centered(|ui| {
                     ui.spacing_mut().item_spacing.y += 12.0;
-                    ui.heading(format!("За месяц потрачено {latte}\u{20bd}"));
+                    let sign = currency_sign(BASE_CURRENCY);
+                    ui.heading(format!("За месяц потрачено {}{sign}",
+                                       format_amount(latte, BASE_CURRENCY)));
+                    
+                    if !form.overspent.is_empty() {
+                        let names: Vec<&str> = form.overspent.iter().map(budget_name).collect();
+                        let warning = format!("⚠ Превышен месячный бюджет: {}", names.join(", "));
+                        ui.horizontal(|ui| {
+                            ui.colored_label(Color32::DARK_RED, warning);
+                            if ui.small_button("✖").clicked() {
+                                form.overspent.clear();
+                            }
+                        });
+                    }
+                    for used in db.budget_uses() {
+                        let limit = format_amount(used.budget.limit, BASE_CURRENCY);
+                        let text = if used.remaining() >= 0 {
+                            format!("{}: осталось {}{sign} из {limit}{sign}", budget_name(&used.budget),
+                                    format_amount(used.remaining() as u64, BASE_CURRENCY))
+                        } else {
+                            format!("{}: перерасход {}{sign} сверх {limit}{sign}", budget_name(&used.budget),
+                                    format_amount(used.remaining().unsigned_abs(), BASE_CURRENCY))
+                        };
+                        let bar = ProgressBar::new(used.spent as f32 / used.budget.limit as f32)
+                            .desired_width(360.0)
+                            .text(text);
+                        ui.add(if used.remaining() < 0 {bar.fill(Color32::DARK_RED)} else {bar});
+                    }
                     if latc == 0 { return; }
                     
-                    ui.label(format!("в {latc} чеках (средний чек {:.2}\u{20bd});",
-                                     (latte as f32) / (latc as f32)));
+                    let average = (latte + latc as u64 / 2) / latc as u64;
+                    ui.label(format!("в {latc} чеках (средний чек {}{sign});",
+                                     format_amount(average, BASE_CURRENCY)));
                     if ui.button("Подробная информация").clicked() {
-                        cmds.push(UiCommands::Go(CurScreen::Stats));
+                        cmds.push(UiCommands::Go(CurScreen::Stats(StatsForm::default())));
                     }
                     ui.add_space(12.0);
                     
-                    db.load_last_spendings(6).for_each(|ml| show_spending_mayload(ui, ml));
+                    let action = db.load_last_spendings(6)
+                        .fold(None, |r, ml| show_spending_mayload(ui, ml).or(r));
+                    if let Some(c) = Self::apply_row_action(db, action) {
+                        cmds.push(c);
+                    }
+                    
+                    if let Some(e) = db.last_revoked() {
+                        let undo = format!("Вернуть удалённое ({}{} на {})",
+                            format_amount(e.client.amount, &e.client.currency),
+                            currency_sign(&e.client.currency),
+                            e.client.group.as_deref().unwrap_or(UNCLASSIFIED));
+                        if ui.button(undo).clicked() {
+                            db.restore_last_revoked();
+                        }
+                    }
                 });
             });
         
         cmds
     }
     
-    fn draw_stat_screen(db: &mut DbView, ctx: &Context) -> Vec<UiCommands> {
+    fn draw_stat_screen(db: &mut DbView, ctx: &Context, form: &mut StatsForm) -> Vec<UiCommands> {
+        use tokio::sync::oneshot::error::TryRecvError;
+        
         let mut cmds = vec![];
         
+        if let Some(pending) = &mut form.export {
+            match pending.try_recv() {
+                Ok(result) => form.exported = Some(result),
+                Err(TryRecvError::Closed) => form.exported = Some(Err("выгрузка прервана".to_owned())),
+                Err(TryRecvError::Empty) => ctx.request_repaint_after(std::time::Duration::from_millis(100)),
+            }
+            if form.exported.is_some() {
+                form.export = None;
+            }
+        }
+        
         TopBottomPanel::bottom("status_bar")
             .min_height(48.0)
             .show(ctx, |ui| {
@@ -219,36 +721,408 @@ impl Trac {
                 ui.vertical_centered(|ui| {
                     ui.spacing_mut().item_spacing.y += 12.0;
                     
-                    if ui.button("Назад").clicked() {
-                        cmds.push(UiCommands::Back);
+                    ui.horizontal(|ui| {
+                        if ui.button("Назад").clicked() {
+                            cmds.push(UiCommands::Back);
+                        }
+                        if ui.button("Курсы валют").clicked() {
+                            cmds.push(UiCommands::Go(CurScreen::Rates(RatesForm::default())));
+                        }
+                        if ui.button("Категории").clicked() {
+                            let form = CategoriesForm::new(db.categories());
+                            cmds.push(UiCommands::Go(CurScreen::Categories(form)));
+                        }
+                        if ui.button("Бюджеты").clicked() {
+                            let form = BudgetsForm::new(&db.budget_uses());
+                            cmds.push(UiCommands::Go(CurScreen::Budgets(form)));
+                        }
+                        
+                        ui.separator();
+                        for (label, format) in [("Выгрузить CSV", ExportFormat::Csv),
+                                                ("Выгрузить JSON", ExportFormat::Json)] {
+                            if ui.add_enabled(form.export.is_none(), Button::new(label)).clicked() {
+                                form.export = Some(db.export(format, form.include_revoked));
+                                form.exported = None;
+                            }
+                        }
+                        ui.checkbox(&mut form.include_revoked, "с отменёнными");
+                    });
+                    match &form.exported {
+                        _ if form.export.is_some() => {ui.label("Выгружаем…");},
+                        Some(Ok(place)) => {ui.label(format!("Выгружено в {place}"));},
+                        Some(Err(e)) => {ui.colored_label(Color32::DARK_RED, format!("Не удалось выгрузить: {e}"));},
+                        None => {},
                     }
                     
                     // 1. displaying aggregate
                     
+                    ui.horizontal(|ui| {
+                        let chosen = form.period;
+                        if ui.selectable_label(matches!(chosen, Period::Month{..}), "Месяц").clicked() {
+                            form.period = Period::Month{back: 0};
+                        }
+                        if ui.selectable_label(matches!(chosen, Period::Week{..}), "Неделя").clicked() {
+                            form.period = Period::Week{back: 0};
+                        }
+                        if ui.selectable_label(matches!(chosen, Period::Days{..}), "Даты").clicked() &&
+                                !matches!(chosen, Period::Days{..}) {
+                            let format = format_description::parse(FORM_DATE).unwrap();
+                            let (from, to) = chosen.bounds(now()).unwrap_or((now(), now()));
+                            let last = to.date().previous_day().unwrap().max(from.date());
+                            form.first = from.date().format(&format).unwrap();
+                            form.last = last.format(&format).unwrap();
+                            form.period = Period::Days{first: from.date(), last};
+                        }
+                        if ui.selectable_label(chosen == Period::Lifetime, "Всё время").clicked() {
+                            form.period = Period::Lifetime;
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        let label = period_label(form.period);
+                        match &mut form.period {
+                            Period::Month{back} | Period::Week{back} => {
+                                if ui.button("◀").clicked() {
+                                    *back += 1;
+                                }
+                                ui.label(label);
+                                if ui.add_enabled(*back > 0, Button::new("▶")).clicked() {
+                                    *back -= 1;
+                                }
+                            },
+                            Period::Days{first, last} => {
+                                let format = format_description::parse(FORM_DATE).unwrap();
+                                ui.add(widgets::TextEdit::singleline(&mut form.first).desired_width(96.0));
+                                ui.label("—");
+                                ui.add(widgets::TextEdit::singleline(&mut form.last).desired_width(96.0));
+                                let typed = time::Date::parse(form.first.trim(), &format).ok()
+                                    .zip(time::Date::parse(form.last.trim(), &format).ok())
+                                    .filter(|(f, l)| f <= l);
+                                match typed {
+                                    Some((f, l)) => (*first, *last) = (f, l),
+                                    None => {ui.colored_label(Color32::DARK_RED, "ГГГГ-ММ-ДД, по порядку");},
+                                }
+                            },
+                            Period::Lifetime => {ui.label(label);},
+                        }
+                    });
+                    
+                    let sign = currency_sign(BASE_CURRENCY);
+                    match db.period_transactions_info(form.period) {
+                        None => {ui.label("Загружаем…");},
+                        Some((total, count)) => {
+                            ui.heading(format!("Потрачено {}{sign} в {count} чеках",
+                                               format_amount(total, BASE_CURRENCY)));
+                        },
+                    }
+                    
+                    if let Some(drilled) = form.drilled.clone() {
+                        let up = parent_category(&drilled).map(|p| p.to_owned());
+                        ui.horizontal(|ui| {
+                            let back = format!("⬅ {}", up.as_deref().unwrap_or("Все категории"));
+                            if ui.button(back).clicked() {
+                                form.drilled = up.clone();
+                            }
+                            ui.strong(drilled);
+                        });
+                    }
+                    
+                    let categories = db.categories().to_vec();
+                    let pie = db.period_pie(form.period, form.drilled.as_deref()).unwrap_or_default();
+                    // Subcategories may share their parent's color, so they are told apart by shade.
+                    let shaded = form.drilled.is_some();
                     pie_chart_with_legend(
                         ui,
-                        db.month_pie().into_iter()
-                          .map(|(group, value)| {
-                              (group, *value as f32, color_cat(&group))
+                        pie.iter().enumerate()
+                          .map(|(i, (group, value, _))| {
+                              let color = color_cat(&categories, group);
+                              let shade = if shaded {(i % 4) as f32 * 0.2} else {0.0};
+                              (group, *value as f32, color.lerp_to_gamma(Color32::WHITE, shade))
                           })
                     );
+                    ui.horizontal_wrapped(|ui| {
+                        for (group, _, _) in pie.iter().filter(|(_, _, deeper)| *deeper) {
+                            let name = group.rsplit(SUBCATEGORY_SEPARATOR).next().unwrap_or(group);
+                            if ui.button(format!("{name} ▸")).clicked() {
+                                form.drilled = Some(group.clone());
+                            }
+                        }
+                    });
+                    
+                    ui.horizontal_wrapped(|ui| {
+                        for (tag, total, _) in db.period_tags(form.period).unwrap_or_default() {
+                            let chosen = form.tag.as_ref() == Some(&tag);
+                            let label = format!("#{tag} {}{sign}", format_amount(total, BASE_CURRENCY));
+                            if ui.selectable_label(chosen, label).clicked() {
+                                form.tag = (!chosen).then_some(tag);
+                            }
+                        }
+                    });
                     
                     // 2. displaying spendings
                     
                     let font = FontId::default();
                     let text_height = ui.fonts(|r| r.row_height(&font));
                     
-                    ScrollArea::vertical().show_rows(ui, text_height,
-                        db.total_live_transactions(),
-                        |ui, range| {
-                            db.load_some_spendings(range.start, range.end)
-                              .for_each(|ml| show_spending_mayload(ui, ml));
+                    let mut action = None;
+                    if let Some(tag) = &form.tag {
+                        match db.tagged(tag) {
+                            None => {ui.label("Загружаем…");},
+                            Some((expenses, (total, count))) => {
+                                ui.label(format!("С меткой #{tag} всего {}{} в {count} чеках",
+                                                 format_amount(total, BASE_CURRENCY), currency_sign(BASE_CURRENCY)));
+                                ScrollArea::vertical().show(ui, |ui| {
+                                    action = expenses.iter().rev()
+                                        .fold(None, |r, e| show_spending_mayload(ui, MayLoad::Confirmed(e)).or(r));
+                                });
+                            },
+                        }
+                    } else {
+                        ScrollArea::vertical().show_rows(ui, text_height,
+                            db.total_live_transactions(),
+                            |ui, range| {
+                                action = db.load_some_spendings(range.start, range.end)
+                                  .fold(None, |r, ml| show_spending_mayload(ui, ml).or(r));
+                            });
+                    }
+                    if let Some(c) = Self::apply_row_action(db, action) {
+                        cmds.push(c);
+                    }
+                });
+            });
+        
+        cmds
+    }
+    
+    fn draw_amend_screen(db: &mut DbView, ctx: &Context, form: &mut AmendForm) -> Vec<UiCommands> {
+        let mut cmds = vec![];
+        
+        CentralPanel::default()
+            .frame(Frame::side_top_panel(&ctx.style())
+                         .inner_margin(Margin::same(18)))
+            .show(ctx, |ui| {
+                ui.vertical_centered_justified(|ui| {
+                    ui.spacing_mut().interact_size.y += 12.0;
+                    ui.spacing_mut().item_spacing.y += 12.0;
+                    
+                    ui.heading("Исправление записи");
+                    amount_editor(ui, &mut form.spent, &form.currency, "Итого: ");
+                    currency_picker(ui, "amend_currency", &mut form.currency, &db.currencies());
+                    ui.add(widgets::TextEdit::singleline(&mut form.category)
+                        .hint_text(UNCLASSIFIED));
+                    ui.add(widgets::TextEdit::singleline(&mut form.time)
+                        .hint_text("ГГГГ-ММ-ДД чч:мм"));
+                    ui.add(widgets::TextEdit::multiline(&mut form.note)
+                        .desired_rows(2)
+                        .hint_text("Комментарий"));
+                    ui.add(widgets::TextEdit::singleline(&mut form.tags)
+                        .hint_text("Метки через запятую"));
+                    
+                    let time = form.parsed_time();
+                    if time.is_none() {
+                        ui.colored_label(Color32::DARK_RED, "Дата должна быть в формате ГГГГ-ММ-ДД чч:мм");
+                    }
+                    
+                    ui.horizontal(|ui| {
+                        if ui.button("Отмена").clicked() {
+                            cmds.push(UiCommands::Back);
+                        }
+                        let save = ui.add_enabled(time.is_some() && form.spent != 0,
+                                                  Button::new("Сохранить"));
+                        if let (true, Some(time)) = (save.clicked(), time) {
+                            let category = normalize_category(&form.category);
+                            let note = form.note.trim();
+                            db.amend_expense(form.expense_id, ClientData{
+                                amount: form.spent,
+                                group: (!category.is_empty()).then_some(category),
+                                revoked: false,
+                                note: (!note.is_empty()).then(|| note.to_owned()),
+                                currency: form.currency.clone(),
+                                tags: parse_tags(&form.tags),
+                            }, time);
+                            cmds.push(UiCommands::Back);
+                        }
+                    });
+                });
+            });
+        
+        cmds
+    }
+    
+    fn draw_rates_screen(db: &mut DbView, ctx: &Context, form: &mut RatesForm) -> Vec<UiCommands> {
+        let mut cmds = vec![];
+        
+        CentralPanel::default()
+            .frame(Frame::side_top_panel(&ctx.style())
+                         .inner_margin(Margin::same(18)))
+            .show(ctx, |ui| {
+                ui.vertical_centered_justified(|ui| {
+                    ui.spacing_mut().item_spacing.y += 12.0;
+                    
+                    if ui.button("Назад").clicked() {
+                        cmds.push(UiCommands::Back);
+                    }
+                    ui.heading("Курсы валют");
+                    ui.label(format!("Сколько {BASE_CURRENCY} стоит единица валюты. Уже \
+                                      записанные расходы сохраняют свой пересчёт."));
+                    
+                    for (currency, rate) in db.exchange_rates() {
+                        ui.monospace(format!("{currency} = {rate}{}", currency_sign(BASE_CURRENCY)));
+                    }
+                    
+                    ui.separator();
+                    ui.add(widgets::TextEdit::singleline(&mut form.currency)
+                        .char_limit(3)
+                        .hint_text("EUR"));
+                    ui.add(widgets::DragValue::new(&mut form.base_per_unit)
+                        .range(0.0001..=1_000_000.0)
+                        .speed(0.01)
+                        .suffix(currency_sign(BASE_CURRENCY)));
+                    
+                    form.currency.make_ascii_uppercase();
+                    let valid = is_currency_code(&form.currency) && form.currency != BASE_CURRENCY;
+                    if ui.add_enabled(valid, Button::new("Сохранить курс")).clicked() {
+                        db.set_exchange_rate(std::mem::take(&mut form.currency), form.base_per_unit);
+                    }
+                });
+            });
+        
+        cmds
+    }
+    
+    fn draw_categories_screen(db: &mut DbView, ctx: &Context, form: &mut CategoriesForm) -> Vec<UiCommands> {
+        let mut cmds = vec![];
+        
+        CentralPanel::default()
+            .frame(Frame::side_top_panel(&ctx.style())
+                         .inner_margin(Margin::same(18)))
+            .show(ctx, |ui| {
+                ui.vertical_centered_justified(|ui| {
+                    ui.spacing_mut().item_spacing.y += 12.0;
+                    
+                    ui.heading("Категории");
+                    ui.label("Предлагаются при записи расходов, по порядку. Уже записанные расходы \
+                              сохраняют свою категорию.");
+                    
+                    let last = form.categories.len().saturating_sub(1);
+                    let mut moved = None;
+                    let mut removed = None;
+                    for (i, c) in form.categories.iter_mut().enumerate() {
+                        ui.horizontal(|ui| {
+                            ui.add(widgets::TextEdit::singleline(&mut c.icon)
+                                .desired_width(32.0)
+                                .hint_text("🛒"));
+                            ui.add(widgets::TextEdit::singleline(&mut c.name)
+                                .desired_width(160.0)
+                                .hint_text("название"));
+                            ui.color_edit_button_srgb(&mut c.color);
+                            if ui.add_enabled(i > 0, Button::new("⬆")).clicked() {
+                                moved = Some(i - 1);
+                            }
+                            if ui.add_enabled(i < last, Button::new("⬇")).clicked() {
+                                moved = Some(i);
+                            }
+                            if ui.button("🗑").clicked() {
+                                removed = Some(i);
+                            }
+                        });
+                    }
+                    if let Some(i) = moved {
+                        form.categories.swap(i, i + 1);
+                    }
+                    if let Some(i) = removed {
+                        form.categories.remove(i);
+                    }
+                    
+                    let room = form.categories.len() < MAX_CATEGORIES;
+                    if ui.add_enabled(room, Button::new("Добавить")).clicked() {
+                        form.categories.push(Category {name: String::new(), icon: String::new(),
+                                                       color: [255, 215, 0]});
+                    }
+                    
+                    let cleaned = form.cleaned();
+                    let valid = categories_valid(&cleaned);
+                    if !valid {
+                        ui.colored_label(Color32::DARK_RED,
+                                         "У каждой категории должны быть значок и своё название");
+                    }
+                    ui.horizontal(|ui| {
+                        if ui.button("Отмена").clicked() {
+                            cmds.push(UiCommands::Back);
+                        }
+                        if ui.add_enabled(valid, Button::new("Сохранить")).clicked() {
+                            db.set_categories(cleaned);
+                            cmds.push(UiCommands::Back);
+                        }
+                    });
+                });
+            });
+        
+        cmds
+    }
+    
+    fn draw_budgets_screen(db: &mut DbView, ctx: &Context, form: &mut BudgetsForm) -> Vec<UiCommands> {
+        let mut cmds = vec![];
+        
+        CentralPanel::default()
+            .frame(Frame::side_top_panel(&ctx.style())
+                         .inner_margin(Margin::same(18)))
+            .show(ctx, |ui| {
+                ui.vertical_centered_justified(|ui| {
+                    ui.spacing_mut().item_spacing.y += 12.0;
+                    
+                    ui.heading("Бюджеты на месяц");
+                    ui.label("Бюджет категории включает её подкатегории; без категории — все расходы.");
+                    
+                    let mut removed = None;
+                    for (i, (category, limit)) in form.budgets.iter_mut().enumerate() {
+                        ui.horizontal(|ui| {
+                            ui.add(widgets::TextEdit::singleline(category)
+                                .desired_width(160.0)
+                                .hint_text("все расходы"));
+                            amount_editor(ui, limit, BASE_CURRENCY, "до ");
+                            if ui.button("🗑").clicked() {
+                                removed = Some(i);
+                            }
                         });
+                    }
+                    if let Some(i) = removed {
+                        form.budgets.remove(i);
+                    }
+                    
+                    if ui.button("Добавить").clicked() {
+                        form.budgets.push((String::new(), 0));
+                    }
+                    
+                    let cleaned = form.cleaned();
+                    let valid = budgets_valid(&cleaned);
+                    if !valid {
+                        ui.colored_label(Color32::DARK_RED,
+                                         "Категории не должны повторяться, а суммы — быть нулевыми");
+                    }
+                    ui.horizontal(|ui| {
+                        if ui.button("Отмена").clicked() {
+                            cmds.push(UiCommands::Back);
+                        }
+                        if ui.add_enabled(valid, Button::new("Сохранить")).clicked() {
+                            db.set_budgets(cleaned);
+                            cmds.push(UiCommands::Back);
+                        }
+                    });
                 });
             });
         
         cmds
     }
+    
+    fn apply_row_action(db: &mut DbView, action: Option<RowAction>) -> Option<UiCommands> {
+        match action? {
+            RowAction::Revoke(time, uid) => {
+                db.revoke_expense(time, uid);
+                None
+            },
+            RowAction::Amend(e) => Some(UiCommands::Go(CurScreen::Amend(AmendForm::new(e)))),
+        }
+    }
 }
 
 impl App for Trac {
@@ -263,13 +1137,40 @@ impl App for Trac {
                 self.screen_buf.push(CurScreen::Main(form));
                 c
             }
-            Some(CurScreen::Stats) => {
-                let c = Self::draw_stat_screen(self.db.as_mut().unwrap(), ctx);
-                self.screen_buf.push(CurScreen::Stats);
+            Some(CurScreen::Stats(mut form)) => {
+                let c = Self::draw_stat_screen(self.db.as_mut().unwrap(), ctx, &mut form);
+                self.screen_buf.push(CurScreen::Stats(form));
                 c
             },
-            Some(CurScreen::Connect) => {
-                self.screen_buf.push(CurScreen::Connect);
+            Some(CurScreen::Amend(mut form)) => {
+                let c = Self::draw_amend_screen(self.db.as_mut().unwrap(), ctx, &mut form);
+                self.screen_buf.push(CurScreen::Amend(form));
+                c
+            },
+            Some(CurScreen::Rates(mut form)) => {
+                let c = Self::draw_rates_screen(self.db.as_mut().unwrap(), ctx, &mut form);
+                self.screen_buf.push(CurScreen::Rates(form));
+                c
+            },
+            Some(CurScreen::Categories(mut form)) => {
+                let c = Self::draw_categories_screen(self.db.as_mut().unwrap(), ctx, &mut form);
+                self.screen_buf.push(CurScreen::Categories(form));
+                c
+            },
+            Some(CurScreen::Budgets(mut form)) => {
+                let c = Self::draw_budgets_screen(self.db.as_mut().unwrap(), ctx, &mut form);
+                self.screen_buf.push(CurScreen::Budgets(form));
+                c
+            },
+            #[cfg(all(feature = "graphics_nowasm", not(feature = "selfhost")))]
+            Some(CurScreen::Connect(mut form)) => {
+                match Self::draw_connect_screen(ctx, &mut form) {
+                    Some(db) => {
+                        self.screen_buf.push(CurScreen::SigningIn(db));
+                        ctx.request_repaint();
+                    },
+                    None => self.screen_buf.push(CurScreen::Connect(form)),
+                }
                 vec![]
             },
             Some(CurScreen::SigningIn(db)) => {
@@ -283,6 +1184,10 @@ impl App for Trac {
         for c in commands {
             match c {
                 UiCommands::Go(to) => {
+                    // Undoing revocation is offered only until main screen is left.
+                    if let Some(db) = &mut self.db {
+                        db.forget_last_revoked();
+                    }
                     self.screen_buf.push(to);
                 },
                 UiCommands::Back => {
@@ -296,7 +1201,22 @@ impl App for Trac {
 
 
 #[cfg(not(target_arch = "wasm32"))]
+#[cfg_attr(all(feature = "graphics_nowasm", not(any(feature = "selfhost", feature = "server"))),
+           allow(dead_code, reason = "remote app starts from the connect screen"))]
 pub fn run_app(db: impl Upstream + 'static) -> eframe::Result {
+    run_native(CurScreen::SigningIn(Box::new(db)))
+}
+
+/// Starts from the connect screen, signing in to a server of user's choice. Must be called within
+/// a Tokio runtime context, where sign-in attempts are spawned.
+#[cfg(all(feature = "graphics_nowasm", not(feature = "selfhost")))]
+#[cfg_attr(feature = "server", allow(dead_code, reason = "embedded server signs in as root"))]
+pub fn run_app_connecting() -> eframe::Result {
+    run_native(CurScreen::Connect(Box::default()))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn run_native(first_screen: CurScreen) -> eframe::Result {
     let icon = include_bytes!("../assets/icon-32.png");
     
     let native_options = eframe::NativeOptions {
@@ -312,10 +1232,10 @@ pub fn run_app(db: impl Upstream + 'static) -> eframe::Result {
         ..Default::default()
     };
     eframe::run_native(
-        "ton.ting.ExpenseExplorer",
+        APP_ID,
         native_options,
         Box::new(|creation_ctx| Ok(Box::new(
-            Trac::new(creation_ctx, db)
+            Trac::new(creation_ctx, first_screen)
         ))),
     )
 }
@@ -341,7 +1261,7 @@ pub fn run_app(db: impl Upstream + 'static) {
                 canvas,
                 web_options,
                 Box::new(|creation_ctx| Ok(Box::new(
-                    Trac::new(creation_ctx, db)
+                    Trac::new(creation_ctx, CurScreen::SigningIn(Box::new(db)))
                 ))),
             )
             .await;
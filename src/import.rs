@@ -0,0 +1,493 @@
+// #[sides(server, client#selfhost)]
+
+use time::{format_description, Date, Month, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::crosstyping::*;
+
+
+/// Which columns of a bank statement hold what, counting from 0.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CsvMapping {
+    pub date: usize,
+    pub amount: usize,
+    pub description: usize,
+    /// Column with currency code; otherwise all amounts are in `default_currency`.
+    #[serde(default)]
+    pub currency: Option<usize>,
+    #[serde(default = "base_currency")]
+    pub default_currency: String,
+    #[serde(default = "comma")]
+    pub delimiter: char,
+    /// In `time` crate syntax, like `[day].[month].[year]`; time of day may follow.
+    #[serde(default = "iso_date")]
+    pub date_format: String,
+    /// Lines to skip before the rows, such as a header.
+    #[serde(default)]
+    pub skip_lines: usize,
+    /// Most banks show spendings as negative amounts, and income as positive one.
+    #[serde(default = "yes")]
+    pub spendings_negative: bool,
+}
+
+fn base_currency() -> String {BASE_CURRENCY.to_owned()}
+fn comma() -> char {','}
+fn iso_date() -> String {"[year]-[month]-[day]".to_owned()}
+fn yes() -> bool {true}
+
+/// Puts statement rows whose description contains `pattern`, ignoring case, into `group`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CategoryRule {
+    pub pattern: String,
+    pub group: String,
+}
+
+/// A spending found in a statement.
+#[derive(Clone, Debug)]
+pub struct StatementRow {
+    pub line: usize,
+    pub time: OffsetDateTime,
+    pub data: ClientData,
+    /// Same for the same row whenever the statement is imported again.
+    pub temp_alias: Uuid,
+}
+
+/// Outcome of importing a statement.
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub duplicates: usize,
+    /// Lines which are not spendings or could not be read, with reasons.
+    pub skipped: Vec<(usize, String)>,
+}
+
+
+/// First rule matching the description decides the group; rows matching none stay unclassified.
+pub fn guess_group(rules: &[CategoryRule], description: &str) -> Option<String> {
+    let description = description.to_lowercase();
+    rules.iter()
+        .find(|rule| description.contains(&rule.pattern.to_lowercase()))
+        .map(|rule| rule.group.clone())
+}
+
+fn alias_of(parts: &[&str]) -> Uuid {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    let digest = hasher.finalize();
+    uuid::Builder::from_custom_bytes(digest[..16].try_into().unwrap()).into_uuid()
+}
+
+/// Names a statement row by its content, so that importing the same statement twice (or two
+/// overlapping ones) stores each spending once. Identical rows are told apart by their order.
+fn row_alias(source: &str, time: OffsetDateTime, data: &ClientData, description: &str, occurrence: usize) -> Uuid {
+    alias_of(&[source, &time.unix_timestamp().to_string(), &data.amount.to_string(), &data.currency,
+               description, &occurrence.to_string()])
+}
+
+/// Counts rows with the same content, so that `row_alias` tells them apart.
+#[derive(Default)]
+struct Occurrences(HashMap<(OffsetDateTime, u64, String, String), usize>);
+impl Occurrences {
+    fn alias(&mut self, source: &str, time: OffsetDateTime, data: &ClientData, description: &str) -> Uuid {
+        let key = (time, data.amount, data.currency.clone(), description.to_owned());
+        let occurrence = self.0.entry(key).or_default();
+        *occurrence += 1;
+        row_alias(source, time, data, description, *occurrence)
+    }
+}
+
+/// Splits one CSV line into fields, honoring double quotes.
+fn split_line(line: &str, delimiter: char) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            },
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// Reads amounts like `-1 234,56`, `1,234.56`, `1,234` or `+99`, returning the sign separately.
+fn parse_signed_amount(text: &str, currency: &str) -> Option<(bool, u64)> {
+    let text: String = text.chars().filter(|c| !c.is_whitespace() && *c != '\'').collect();
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(&text)),
+    };
+    // With both separators present, the first one groups thousands. A lone one does so when it
+    // repeats, or when three digits follow it and the currency has no third decimal place.
+    let groups = |sep: char| {
+        let tail = text.rsplit(sep).next().unwrap_or("");
+        text.matches(sep).count() > 1 || tail.len() == 3 && currency_exponent(currency) < 3
+    };
+    let text = match (text.find('.'), text.find(',')) {
+        (Some(dot), Some(comma)) => text.replace(if dot < comma {'.'} else {','}, ""),
+        (Some(_), None) if groups('.') => text.replace('.', ""),
+        (None, Some(_)) if groups(',') => text.replace(',', ""),
+        _ => text.to_owned(),
+    };
+    Some((negative, parse_amount(&text, currency)?))
+}
+
+fn parse_date(text: &str, format: &str) -> Result<OffsetDateTime> {
+    let format = format_description::parse_borrowed::<2>(format).context("invalid date format")?;
+    let text = text.trim();
+    if let Ok(datetime) = PrimitiveDateTime::parse(text, &format) {
+        return Ok(datetime.assume_utc());
+    }
+    // Statements without time of day are placed at noon, so the date holds in any timezone.
+    let date = Date::parse(text, &format).with_context(|| format!("cannot read date {text:?}"))?;
+    Ok(date.with_hms(12, 0, 0).unwrap().assume_utc())
+}
+
+/// Makes an expense of a statement amount, unless it is income or a refund.
+fn spending(amount_text: &str, currency: String, spendings_negative: bool, description: &str,
+            rules: &[CategoryRule]) -> Result<ClientData> {
+    ensure!(is_currency_code(&currency), "{currency:?} is not a currency code");
+    let Some((negative, amount)) = parse_signed_amount(amount_text, &currency) else {
+        bail!("cannot read amount {amount_text:?}");
+    };
+    if negative != spendings_negative || amount == 0 {
+        bail!("not a spending");
+    }
+    Ok(ClientData {
+        amount,
+        group: guess_group(rules, description),
+        revoked: false,
+        note: Some(description.to_owned()).filter(|d| !d.is_empty()),
+        currency,
+        tags: vec![],
+    })
+}
+
+/// Payee and memo make one description, without repeating what they share.
+fn describe(payee: Option<&str>, memo: Option<&str>) -> String {
+    match (payee, memo) {
+        (Some(payee), Some(memo)) if !payee.contains(memo) => format!("{payee} — {memo}"),
+        (Some(payee), _) => payee.to_owned(),
+        (None, memo) => memo.unwrap_or_default().to_owned(),
+    }
+}
+
+/// Finds spendings in a CSV statement; lines which are not spendings are reported in `skipped`.
+pub fn parse_csv(text: &str, mapping: &CsvMapping, rules: &[CategoryRule], skipped: &mut Vec<(usize, String)>)
+        -> Result<Vec<StatementRow>> {
+    ensure!(is_currency_code(&mapping.default_currency), "{:?} is not a currency code",
+            mapping.default_currency);
+    format_description::parse_borrowed::<2>(&mapping.date_format).context("invalid date format")?;
+    
+    let mut occurrences = Occurrences::default();
+    let mut rows = vec![];
+    for (i, line) in text.lines().enumerate().skip(mapping.skip_lines) {
+        let line_no = i + 1;
+        if line.trim().is_empty() {continue;}
+        
+        let row: Result<_> = (|| {
+            let fields = split_line(line.trim_end_matches('\r'), mapping.delimiter);
+            let field = |column: usize| fields.get(column).map(|f| f.trim())
+                .with_context(|| format!("no column {column}"));
+            
+            let currency = match mapping.currency {
+                Some(column) => field(column)?.to_uppercase(),
+                None => mapping.default_currency.clone(),
+            };
+            let time = parse_date(field(mapping.date)?, &mapping.date_format)?;
+            let description = field(mapping.description)?.to_owned();
+            let data = spending(field(mapping.amount)?, currency, mapping.spendings_negative,
+                                &description, rules)?;
+            Ok((time, data, description))
+        })();
+        
+        match row {
+            Ok((time, data, description)) => {
+                let temp_alias = occurrences.alias("csv", time, &data, &description);
+                rows.push(StatementRow {line: line_no, time, data, temp_alias});
+            },
+            Err(e) => skipped.push((line_no, format!("{e:#}"))),
+        }
+    }
+    Ok(rows)
+}
+
+
+// ---------------------------------------------------------------------------------------------- //
+// OFX (and QFX, which is the same) statements. Version 1 is SGML where elements are not closed,
+// version 2 is XML; both are read by looking for tags, which is enough for statement downloads.
+
+/// Text after the first `<TAG>` in `ofx`, up to the next tag.
+fn ofx_field<'a>(ofx: &'a str, tag: &str) -> Option<&'a str> {
+    let start = ofx.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let rest = &ofx[start..];
+    let value = rest[..rest.find('<').unwrap_or(rest.len())].trim();
+    Some(value).filter(|v| !v.is_empty())
+}
+
+fn ofx_text(value: &str) -> String {
+    value.replace("&lt;", "<").replace("&gt;", ">").replace("&nbsp;", " ").replace("&amp;", "&")
+}
+
+/// Reads `YYYYMMDD[HHMMSS[.XXX]][[gmt offset[:tz name]]]`; times without offset are in UTC.
+fn parse_ofx_date(text: &str) -> Result<OffsetDateTime> {
+    let (stamp, zone) = match text.split_once('[') {
+        Some((stamp, zone)) => (stamp, Some(zone.trim_end_matches(']'))),
+        None => (text, None),
+    };
+    let digits = stamp.split('.').next().unwrap_or_default();
+    ensure!(digits.len() >= 8 && digits.bytes().all(|b| b.is_ascii_digit()), "cannot read date {text:?}");
+    
+    let date = Date::parse(&digits[..8], &format_description::parse_borrowed::<2>("[year][month][day]")?)
+        .with_context(|| format!("cannot read date {text:?}"))?;
+    if digits.len() < 14 {
+        return Ok(date.with_hms(12, 0, 0).unwrap().assume_utc());
+    }
+    let time = Time::parse(&digits[8..14], &format_description::parse_borrowed::<2>("[hour][minute][second]")?)
+        .with_context(|| format!("cannot read time {text:?}"))?;
+    let hours: f64 = match zone {
+        Some(zone) => zone.split(':').next().unwrap_or_default().trim().parse()
+            .with_context(|| format!("cannot read timezone {text:?}"))?,
+        None => 0.0,
+    };
+    let offset = UtcOffset::from_whole_seconds((hours * 3600.0).round() as i32)?;
+    Ok(PrimitiveDateTime::new(date, time).assume_offset(offset).to_offset(UtcOffset::UTC))
+}
+
+/// Finds spendings in an OFX statement, which may list several accounts. Transactions are named by
+/// their FITID, unique within an account, so importing them again is always recognized.
+pub fn parse_ofx(text: &str, rules: &[CategoryRule], skipped: &mut Vec<(usize, String)>)
+        -> Result<Vec<StatementRow>> {
+    ensure!(text.contains("<OFX>"), "not an OFX file");
+    let line_of = |offset: usize| text[..offset].matches('\n').count() + 1;
+    
+    // Statement responses (`STMTRS` for bank accounts, `CCSTMTRS` for cards) start with the
+    // account and its currency, followed by transactions.
+    let mut starts: Vec<usize> = text.match_indices("<STMTRS>").chain(text.match_indices("<CCSTMTRS>"))
+        .map(|(i, _)| i).collect();
+    starts.sort();
+    starts.push(text.len());
+    
+    let mut occurrences = Occurrences::default();
+    let mut rows = vec![];
+    for bounds in starts.windows(2) {
+        let statement = &text[bounds[0]..bounds[1]];
+        let header = &statement[..statement.find("<STMTTRN>").unwrap_or(statement.len())];
+        let account = ofx_field(header, "ACCTID").unwrap_or_default();
+        let default_currency = ofx_field(header, "CURDEF").unwrap_or(BASE_CURRENCY);
+        
+        for (offset, _) in statement.match_indices("<STMTTRN>") {
+            let line_no = line_of(bounds[0] + offset);
+            let transaction = &statement[offset..];
+            let transaction = &transaction[..transaction.find("</STMTTRN>").unwrap_or(transaction.len())];
+            
+            let row: Result<_> = (|| {
+                // Amounts are in the transaction's own currency if it is given.
+                let currency = transaction.find("<CURRENCY>")
+                    .and_then(|i| ofx_field(&transaction[i..], "CURSYM"))
+                    .unwrap_or(default_currency).to_uppercase();
+                let time = parse_ofx_date(ofx_field(transaction, "DTPOSTED").context("no DTPOSTED")?)?;
+                let description = describe(ofx_field(transaction, "NAME").map(ofx_text).as_deref(),
+                                           ofx_field(transaction, "MEMO").map(ofx_text).as_deref());
+                let amount_text = ofx_field(transaction, "TRNAMT").context("no TRNAMT")?;
+                let data = spending(amount_text, currency, true, &description, rules)?;
+                Ok((time, data, description))
+            })();
+            
+            match row {
+                Ok((time, data, description)) => {
+                    let temp_alias = match ofx_field(transaction, "FITID") {
+                        Some(fitid) => alias_of(&["ofx", account, fitid]),
+                        None => occurrences.alias("ofx", time, &data, &description),
+                    };
+                    rows.push(StatementRow {line: line_no, time, data, temp_alias});
+                },
+                Err(e) => skipped.push((line_no, format!("{e:#}"))),
+            }
+        }
+    }
+    Ok(rows)
+}
+
+
+// ---------------------------------------------------------------------------------------------- //
+// QIF files: records of `<letter><value>` lines ending with `^`, in sections started by `!Type:`.
+
+/// QIF dates are written as the exporting program likes: `1/15/25`, `1/15'2025`, `15.01.2025` or
+/// `2025-01-15`. Slashes mean American order; `'` before the year means 2000s.
+fn parse_qif_date(text: &str) -> Result<OffsetDateTime> {
+    let compact = text.replace(' ', "");
+    let parts: Vec<&str> = compact.split(['/', '\'', '.', '-']).collect();
+    let [a, b, c] = parts[..] else {bail!("cannot read date {text:?}")};
+    let (year, month, day) = match (compact.contains('.'), compact.contains('-') && a.len() == 4) {
+        (true, _) => (c, b, a),
+        (_, true) => (a, b, c),
+        _ => (c, a, b),
+    };
+    let number = |part: &str| part.parse::<u16>().with_context(|| format!("cannot read date {text:?}"));
+    let small = |part: &str| part.parse::<u8>().with_context(|| format!("cannot read date {text:?}"));
+    let year = match (number(year)?, year.len()) {
+        (y, 4) => y as i32,
+        (y, _) if compact.contains('\'') || y < 70 => 2000 + y as i32,
+        (y, _) => 1900 + y as i32,
+    };
+    let month = Month::try_from(small(month)?).with_context(|| format!("no month in {text:?}"))?;
+    let date = Date::from_calendar_date(year, month, small(day)?)
+        .with_context(|| format!("cannot read date {text:?}"))?;
+    Ok(date.with_hms(12, 0, 0).unwrap().assume_utc())
+}
+
+/// Finds spendings in a QIF file. QIF names no currency, so all amounts are in `currency`; neither
+/// does it identify transactions, so they are told apart by content, like CSV rows.
+pub fn parse_qif(text: &str, currency: &str, rules: &[CategoryRule], skipped: &mut Vec<(usize, String)>)
+        -> Result<Vec<StatementRow>> {
+    ensure!(is_currency_code(currency), "{currency:?} is not a currency code");
+    ensure!(text.trim_start().starts_with('!'), "not a QIF file");
+    
+    #[derive(PartialEq)]
+    enum Section {Transactions, Investments, Other}
+    let mut section = Section::Other;
+    let mut record: Vec<(usize, char, &str)> = vec![];
+    let mut occurrences = Occurrences::default();
+    let mut rows = vec![];
+    
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('!') {
+            section = match header.trim() {
+                "Type:Bank" | "Type:Cash" | "Type:CCard" | "Type:Oth A" | "Type:Oth L" => Section::Transactions,
+                "Type:Invst" => Section::Investments,
+                h if h.starts_with("Type:") || h == "Account" => Section::Other,
+                _ => continue,    // options like `!Option:AutoSwitch` keep the section
+            };
+            record.clear();
+            continue;
+        }
+        if line != "^" {
+            if let Some(code) = line.chars().next() {
+                record.push((i + 1, code, &line[code.len_utf8()..]));
+            }
+            continue;
+        }
+        
+        let Some(&(line_no, _, _)) = record.first() else {continue};
+        if section == Section::Other {
+            record.clear();
+            continue;
+        }
+        let field = |code: char| record.iter().find(|(_, c, _)| *c == code).map(|(_, _, v)| v.trim())
+            .filter(|v| !v.is_empty());
+        let row: Result<_> = (|| {
+            ensure!(section == Section::Transactions, "investment transactions are not imported");
+            let time = parse_qif_date(field('D').context("no date")?)?;
+            let description = describe(field('P'), field('M'));
+            let amount_text = field('T').or(field('U')).context("no amount")?;
+            let data = spending(amount_text, currency.to_owned(), true, &description, rules)?;
+            Ok((time, data, description))
+        })();
+        match row {
+            Ok((time, data, description)) => {
+                let temp_alias = occurrences.alias("qif", time, &data, &description);
+                rows.push(StatementRow {line: line_no, time, data, temp_alias});
+            },
+            Err(e) => skipped.push((line_no, format!("{e:#}"))),
+        }
+        record.clear();
+    }
+    Ok(rows)
+}
+
+
+// ---------------------------------------------------------------------------------------------- //
+// Self-hosted side, importing a statement file into the local database.
+
+#[cfg(feature = "selfhost")]
+pub use client::*;
+
+#[cfg(feature = "selfhost")]
+mod client {
+    use anyhow::{bail, Context, Result};
+    use std::path::PathBuf;
+    
+    use super::{parse_csv, parse_ofx, parse_qif, CategoryRule, CsvMapping, ImportReport};
+    use crate::selfhost::SingleUserSqlite;
+    use crate::crosstyping::*;
+
+
+    const USAGE: &str = "\
+Usage: ting-expense-a import STATEMENT [--currency CODE] [--mapping FILE] [--rules FILE]
+                             [--database FILE]
+
+Adds spendings from an OFX, QFX, QIF or CSV STATEMENT to the local database. Spendings imported before
+are skipped, so a statement may be imported again as it grows.
+  --currency CODE  currency of QIF amounts, base one by default (OFX names its own)
+  --mapping FILE   reads STATEMENT as CSV, with columns described by JSON like
+                   {\"date\": 0, \"amount\": 1, \"description\": 2}
+  --rules FILE     JSON list of {\"pattern\": ..., \"group\": ...}, putting spendings whose
+                   description contains the pattern into the group";
+    
+    /// Runs `ting-expense-a import ...` command line.
+    pub fn run_command(args: &[String]) -> Result<()> {
+        let mut positional = vec![];
+        let mut currency = BASE_CURRENCY.to_owned();
+        let (mut mapping, mut rules, mut database) = (None, vec![], None);
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" | "-h" => {
+                    println!("{USAGE}");
+                    return Ok(());
+                },
+                "--currency" => currency = args.next().context(USAGE)?.to_uppercase(),
+                "--mapping" => {
+                    let path = args.next().context(USAGE)?;
+                    let text = std::fs::read_to_string(path).with_context(|| format!("cannot read {path}"))?;
+                    mapping = Some(serde_json::from_str::<CsvMapping>(&text)
+                        .with_context(|| format!("cannot read mapping from {path}"))?);
+                },
+                "--rules" => {
+                    let path = args.next().context(USAGE)?;
+                    let text = std::fs::read_to_string(path).with_context(|| format!("cannot read {path}"))?;
+                    rules = serde_json::from_str::<Vec<CategoryRule>>(&text)
+                        .with_context(|| format!("cannot read rules from {path}"))?;
+                },
+                "--database" => database = Some(PathBuf::from(args.next().context(USAGE)?)),
+                flag if flag.starts_with("--") => bail!("unknown flag {flag}\n\n{USAGE}"),
+                _ => positional.push(arg.as_str()),
+            }
+        }
+        let [statement] = positional[..] else {bail!("{USAGE}")};
+        let path = database.or_else(crate::selfhost::default_path)
+            .context("no storage directory for the database")?;
+        
+        // OFX 1.x files are often in a legacy encoding; names may get garbled, amounts do not.
+        let bytes = std::fs::read(statement).with_context(|| format!("cannot read {statement}"))?;
+        let text = String::from_utf8_lossy(&bytes);
+        let mut report = ImportReport::default();
+        let rows = match mapping {
+            Some(mapping) => parse_csv(&text, &mapping, &rules, &mut report.skipped)?,
+            None if text.contains("<OFX>") => parse_ofx(&text, &rules, &mut report.skipped)?,
+            None => parse_qif(&text, &currency, &rules, &mut report.skipped)?,
+        };
+        
+        SingleUserSqlite::open(&path)?.import_rows(rows, &mut report);
+        for (line, reason) in &report.skipped {
+            eprintln!("{statement}:{line}: {reason}");
+        }
+        println!("Imported {} spendings, {} were imported before, {} lines skipped.",
+                 report.imported, report.duplicates, report.skipped.len());
+        Ok(())
+    }
+}
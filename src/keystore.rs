@@ -0,0 +1,91 @@
+// #[sides(client#not-selfhost)]
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use anyhow::{anyhow, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use postcard::{to_stdvec, from_bytes};
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use ring::pbkdf2;
+
+
+// File layout: MAGIC, salt, nonce, then sealed postcard-encoded `Credentials` with its tag.
+// The header is authenticated as associated data, so it cannot be swapped unnoticed.
+const MAGIC: &[u8; 8] = b"TEAKEYS1";
+const SALT_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + SALT_LEN + NONCE_LEN;
+const KDF_ROUNDS: NonZeroU32 = NonZeroU32::new(600_000).unwrap();
+
+
+/// Everything needed to sign in to a server without asking the user.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Credentials {
+    pub url: String,
+    pub device: String,
+    pub secret: Vec<u8>,    // TOTP key
+}
+
+/// Keystore location in the app's storage directory.
+pub fn keystore_path() -> Option<PathBuf> {
+    eframe::storage_dir(crate::graphics::APP_ID).map(|dir| dir.join("credentials.keystore"))
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> LessSafeKey {
+    let mut key = [0_u8; 32];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, KDF_ROUNDS, salt, passphrase.as_bytes(), &mut key);
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap())
+}
+
+/// Encrypts credentials under the passphrase, replacing whatever the keystore held.
+/// Slow by design; better not to call it on GUI thread.
+pub fn save(credentials: &Credentials, passphrase: &str) -> Result<()> {
+    let path = keystore_path().context("no storage directory for the keystore")?;
+    
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.resize(HEADER_LEN, 0);
+    getrandom::fill(&mut header[MAGIC.len()..]).map_err(|e| anyhow!("no randomness source: {e}"))?;
+    let (salt, nonce) = header[MAGIC.len()..].split_at(SALT_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).unwrap();
+    
+    let mut sealed = to_stdvec(credentials)?;
+    derive_key(passphrase, salt)
+        .seal_in_place_append_tag(nonce, Aad::from(&header), &mut sealed)
+        .map_err(|_| anyhow!("cannot encrypt credentials"))?;
+    
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)] std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&path)
+        .with_context(|| format!("cannot write keystore {}", path.display()))?;
+    std::io::Write::write_all(&mut file, &[header, sealed].concat())?;
+    Ok(())
+}
+
+/// Decrypts stored credentials. Slow by design, like `save`.
+pub fn load(passphrase: &str) -> Result<Credentials> {
+    let path = keystore_path().context("no storage directory for the keystore")?;
+    let mut content = std::fs::read(&path)
+        .with_context(|| format!("cannot read keystore {}", path.display()))?;
+    ensure!(content.len() > HEADER_LEN && content.starts_with(MAGIC), "keystore is damaged");
+    
+    let (header, sealed) = content.split_at_mut(HEADER_LEN);
+    let (salt, nonce) = header[MAGIC.len()..].split_at(SALT_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).unwrap();
+    let plain = derive_key(passphrase, salt)
+        .open_in_place(nonce, Aad::from(&*header), sealed)
+        .map_err(|_| anyhow!("wrong passphrase"))?;
+    Ok(from_bytes(plain)?)
+}
+
+pub fn exists() -> bool {
+    keystore_path().is_some_and(|path| path.exists())
+}
+
+pub fn forget() -> Result<()> {
+    let path = keystore_path().context("no storage directory for the keystore")?;
+    std::fs::remove_file(&path).with_context(|| format!("cannot remove keystore {}", path.display()))
+}
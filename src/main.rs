@@ -10,15 +10,24 @@ compile_error!("WASM application does not provide a expense-storing server.");
 
 #[cfg(all(feature = "graphics_wasm", not(feature = "selfhost")))] mod remotehost_wasm;
 #[cfg(all(feature = "graphics_nowasm", not(feature = "selfhost")))] mod remotehost;
+#[cfg(all(feature = "graphics_nowasm", not(feature = "selfhost")))] mod keystore;
+#[cfg(all(feature = "graphics", not(feature = "selfhost")))] mod outbox;
 #[cfg(feature = "selfhost")] mod selfhost;
 #[cfg(feature = "graphics")] mod db_slice;
 #[cfg(feature = "graphics")] mod graphics;
 #[cfg(feature = "graphics")] mod widgets;
 #[cfg(feature = "server")] mod server;
+#[cfg(any(feature = "server", feature = "selfhost"))] mod categories;
+#[cfg(any(feature = "server", feature = "selfhost"))] mod export;
+#[cfg(any(feature = "server", feature = "selfhost"))] mod import;
+#[cfg(any(feature = "server", feature = "selfhost"))] mod migrations;
+#[cfg(any(feature = "server", feature = "selfhost"))] mod stats;
+#[cfg(any(feature = "server", feature = "selfhost"))] mod sync;
+#[cfg(any(feature = "server", feature = "selfhost"))] mod tags;
 mod crosstyping;
 
 #[cfg(all(feature = "graphics_wasm", not(feature = "selfhost")))] use remotehost_wasm::RemoteDatabase;
-#[cfg(all(feature = "graphics_nowasm", not(feature = "selfhost")))] use remotehost::RemoteDatabase;
+#[cfg(all(feature = "graphics_nowasm", feature = "server"))] use remotehost::RemoteDatabase;
 
 
 
@@ -34,15 +43,34 @@ fn main() {
 
 #[cfg(all(feature = "graphics_nowasm", feature = "selfhost"))]
 fn main() {
-    let db = selfhost::SingleUserSqlite::default();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match args.first().map(String::as_str) {
+        Some("sync")   => Some(sync::run_command(&args[1..])),
+        Some("import") => Some(import::run_command(&args[1..])),
+        Some("export") => Some(export::run_command(&args[1..])),
+        _ => None,
+    };
+    if let Some(result) = command {
+        if let Err(e) = result {
+            eprintln!("{e:#}");
+            std::process::exit(2);
+        }
+        return;
+    }
+    
+    let db = match selfhost::default_path().map(|path| selfhost::SingleUserSqlite::open(&path)) {
+        Some(Ok(db)) => db,
+        Some(Err(e)) => panic!("{e:#}"),
+        None => selfhost::SingleUserSqlite::default(),
+    };
     graphics::run_app(db).unwrap();
 }
 
 #[cfg(all(feature = "graphics_nowasm", not(feature = "server"), not(feature = "selfhost")))]
-#[tokio::main]
-async fn main() {
-    let db = RemoteDatabase::connect(todo!("url"), todo!("credentials")).await;
-    graphics::run_app(db);
+fn main() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _context = runtime.enter();
+    graphics::run_app_connecting().unwrap();
 }
 
 #[cfg(all(feature = "graphics_nowasm", feature = "server"))]
@@ -51,10 +79,13 @@ fn main() {
     
     let runtime = tokio::runtime::Runtime::new().unwrap();
     let (root_send, root_recv) = tokio::sync::oneshot::channel();
-    runtime.spawn(server::serve_forever("0.0.0.0:4341", vec![1_u8; 64], Some(root_send)));
+    let config = server::ServerConfig::ephemeral("0.0.0.0:4341");
+    runtime.spawn(server::serve_forever(config, Some(root_send)));
     let db = runtime.block_on(async {
-        let root_credentials = root_recv.await.expect("TEA root account was not generated");
-        RemoteDatabase::connect("http://127.0.0.1:4341", root_credentials).await
+        let (device, secret) = root_recv.await.expect("TEA root account was not generated");
+        let url = "http://127.0.0.1:4341".to_owned();
+        RemoteDatabase::connect(&keystore::Credentials {url, device: device.to_owned(), secret}).await
+            .expect("cannot connect to the embedded server")
     });
     
     graphics::run_app(db).unwrap();
@@ -63,7 +94,15 @@ fn main() {
 #[cfg(all(not(feature = "graphics"), feature = "server"))]
 #[tokio::main]
 async fn main() {
-    println!("Will serve on 0.0.0.0:4341.");
-    server::serve_forever("0.0.0.0:4341", vec![1_u8; 64], None).await;
+    let config = match server::ServerConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(2);
+        }
+    };
+    println!("Will serve on {}, storing expenses in {}.", config.bind,
+             config.database.as_deref().unwrap_or("memory".as_ref()).display());
+    server::serve_forever(config, None).await;
 }
 
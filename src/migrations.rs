@@ -0,0 +1,256 @@
+// #[sides(server, client#selfhost)]
+
+use rusqlite::{Connection, TransactionBehavior};
+use anyhow::{ensure, Result, Context};
+
+
+// Schema versions are tracked in `PRAGMA user_version`; the N-th step upgrades a database from
+// version N to N+1. Steps are append-only: once released, a step must never be edited, only
+// followed by another one.
+//
+// The first steps use `IF NOT EXISTS` because files created before versioning was introduced
+// already contain these tables while reporting version 0.
+
+const SPENDING_RECORDS_V1: &str = "
+CREATE TABLE IF NOT EXISTS spending_records (
+    id        BLOB PRIMARY KEY  DEFAULT(randomblob(16)),
+    principal TEXT              DEFAULT NULL,
+    unix_date TEXT              DEFAULT(datetime('now')),
+    amount_indivisible INT8,
+    spend_group        TEXT,
+    revoked            BOOL     DEFAULT FALSE
+);
+CREATE INDEX IF NOT EXISTS live_records ON spending_records(principal, revoked, unix_date);
+CREATE INDEX IF NOT EXISTS aggregate_records ON spending_records(principal, revoked, spend_group, unix_date);
+";
+
+#[cfg(feature = "server")]
+const USERS_V1: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    device    TEXT PRIMARY KEY NOT NULL,
+    principal TEXT             NOT NULL,
+    totp_key  BLOB             DEFAULT(randomblob(24))
+);
+CREATE INDEX IF NOT EXISTS enum_devices ON users(principal);
+";
+
+// Previous values of amended expenses, as an audit trail.
+const AMENDMENTS_V1: &str = "
+CREATE TABLE spending_amendments (
+    expense_id BLOB NOT NULL REFERENCES spending_records(id),
+    amended_at TEXT DEFAULT(datetime('now')),
+    unix_date  TEXT,
+    amount_indivisible INT8,
+    spend_group        TEXT
+);
+CREATE INDEX amendments_of ON spending_amendments(expense_id, amended_at);
+";
+
+const NOTES_V1: &str = "
+ALTER TABLE spending_records ADD COLUMN note TEXT DEFAULT NULL;
+ALTER TABLE spending_amendments ADD COLUMN note TEXT DEFAULT NULL;
+";
+
+// Rates are kept per principal, in base currency units per one unit of `currency`; base currency
+// itself is implicitly at par. Existing records predate currencies, so they are all in base one.
+const CURRENCIES_V1: &str = "
+ALTER TABLE spending_records ADD COLUMN currency TEXT NOT NULL DEFAULT 'RUB';
+ALTER TABLE spending_records ADD COLUMN amount_base INT8;
+UPDATE spending_records SET amount_base = amount_indivisible;
+ALTER TABLE spending_amendments ADD COLUMN currency TEXT NOT NULL DEFAULT 'RUB';
+ALTER TABLE spending_amendments ADD COLUMN amount_base INT8;
+UPDATE spending_amendments SET amount_base = amount_indivisible;
+
+CREATE TABLE exchange_rates (
+    principal     TEXT DEFAULT NULL,
+    currency      TEXT NOT NULL,
+    base_per_unit REAL NOT NULL,
+    UNIQUE(principal, currency)
+);
+";
+
+// Amounts were whole units so far; from now on they are minor ones. Exponents are as
+// `currency_exponent` knew them when this step was written.
+const MINOR_UNITS_V1: &str = "
+UPDATE spending_records SET amount_base = amount_base * 100;
+UPDATE spending_records SET amount_indivisible = amount_indivisible * CASE
+    WHEN currency IN ('BIF', 'CLP', 'DJF', 'GNF', 'ISK', 'JPY', 'KMF', 'KRW', 'PYG', 'RWF', 'UGX',
+                      'UYI', 'VND', 'VUV', 'XAF', 'XOF', 'XPF') THEN 1
+    WHEN currency IN ('BHD', 'IQD', 'JOD', 'KWD', 'LYD', 'OMR', 'TND') THEN 1000
+    ELSE 100
+END;
+UPDATE spending_amendments SET amount_base = amount_base * 100;
+UPDATE spending_amendments SET amount_indivisible = amount_indivisible * CASE
+    WHEN currency IN ('BIF', 'CLP', 'DJF', 'GNF', 'ISK', 'JPY', 'KMF', 'KRW', 'PYG', 'RWF', 'UGX',
+                      'UYI', 'VND', 'VUV', 'XAF', 'XOF', 'XPF') THEN 1
+    WHEN currency IN ('BHD', 'IQD', 'JOD', 'KWD', 'LYD', 'OMR', 'TND') THEN 1000
+    ELSE 100
+END;
+";
+
+// Clients name each submission with a `temp_alias`, so that a resubmitted expense is recognized.
+// Self-hosted records have no principal, which must still not make aliases distinct.
+const TEMP_ALIASES_V1: &str = "
+ALTER TABLE spending_records ADD COLUMN temp_alias BLOB DEFAULT NULL;
+CREATE UNIQUE INDEX submissions ON spending_records(ifnull(principal, ''), temp_alias);
+";
+
+// Every insertion or change of a record stamps it with the next value of `change_counter`, so that
+// a peer which has seen changes up to some value asks only for later ones. Existing records are
+// stamped in insertion order. Only content columns are watched, so stamping does not recurse.
+const CHANGE_CURSOR_V1: &str = "
+CREATE TABLE change_counter (value INTEGER NOT NULL);
+INSERT INTO change_counter SELECT ifnull(max(rowid), 0) FROM spending_records;
+ALTER TABLE spending_records ADD COLUMN changed INTEGER NOT NULL DEFAULT 0;
+UPDATE spending_records SET changed = rowid;
+CREATE INDEX changes ON spending_records(principal, changed);
+
+CREATE TRIGGER record_inserted AFTER INSERT ON spending_records BEGIN
+    UPDATE change_counter SET value = value + 1;
+    UPDATE spending_records SET changed = (SELECT value FROM change_counter) WHERE rowid = NEW.rowid;
+END;
+CREATE TRIGGER record_changed AFTER UPDATE OF unix_date, amount_indivisible, spend_group, revoked,
+                                              note, currency, amount_base ON spending_records BEGIN
+    UPDATE change_counter SET value = value + 1;
+    UPDATE spending_records SET changed = (SELECT value FROM change_counter) WHERE rowid = NEW.rowid;
+END;
+";
+
+// How far a self-hosted database is synchronized with each server: `pulled` is the server's
+// change counter, `pushed` is the local one.
+#[cfg(any(feature = "selfhost", test))]
+const SYNC_PEERS_V1: &str = "
+CREATE TABLE sync_peers (
+    server TEXT PRIMARY KEY NOT NULL,
+    pulled INTEGER NOT NULL DEFAULT 0,
+    pushed INTEGER NOT NULL DEFAULT 0
+);
+";
+
+// Categories offered to each principal, in `sort_order`; `color` is 0xRRGGBB. Everyone starts with
+// the ones which were built into clients so far.
+const CATEGORIES_V1: &str = "
+CREATE TABLE categories (
+    principal  TEXT DEFAULT NULL,
+    name       TEXT NOT NULL,
+    icon       TEXT NOT NULL DEFAULT '',
+    color      INT  NOT NULL DEFAULT 16766720,
+    sort_order INT  NOT NULL DEFAULT 0
+);
+CREATE UNIQUE INDEX categories_of ON categories(ifnull(principal, ''), name);
+";
+
+#[cfg(feature = "server")]
+const DEFAULT_CATEGORIES_V1: &str = "
+INSERT INTO categories(principal, name, icon, color, sort_order)
+    SELECT principal, column1, column2, column3, column4
+    FROM (SELECT DISTINCT principal FROM users)
+    CROSS JOIN (VALUES ('еду', '🍞', 65280, 0), ('хозтовары', '🏡', 6316128, 1),
+                       ('транспорт', '🚋', 16753920, 2));
+";
+
+#[cfg(any(feature = "selfhost", test))]
+const SELFHOST_DEFAULT_CATEGORIES_V1: &str = "
+INSERT INTO categories(principal, name, icon, color, sort_order)
+    VALUES (NULL, 'еду', '🍞', 65280, 0), (NULL, 'хозтовары', '🏡', 6316128, 1),
+           (NULL, 'транспорт', '🚋', 16753920, 2);
+";
+
+// Tags are kept with each record as a JSON array, so that they travel along with the rest of it;
+// triggers mirror them into `expense_tags` for filtering and totals. Tag changes count as record
+// changes for synchronization.
+const TAGS_V1: &str = "
+ALTER TABLE spending_records ADD COLUMN tags TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE spending_amendments ADD COLUMN tags TEXT NOT NULL DEFAULT '[]';
+
+CREATE TABLE expense_tags (
+    expense_id BLOB NOT NULL REFERENCES spending_records(id),
+    tag        TEXT NOT NULL,
+    PRIMARY KEY(expense_id, tag)
+);
+CREATE INDEX tagged ON expense_tags(tag, expense_id);
+
+CREATE TRIGGER tags_inserted AFTER INSERT ON spending_records BEGIN
+    INSERT OR IGNORE INTO expense_tags(expense_id, tag) SELECT NEW.id, value FROM json_each(NEW.tags);
+END;
+CREATE TRIGGER tags_changed AFTER UPDATE OF tags ON spending_records BEGIN
+    DELETE FROM expense_tags WHERE expense_id = NEW.id;
+    INSERT OR IGNORE INTO expense_tags(expense_id, tag) SELECT NEW.id, value FROM json_each(NEW.tags);
+END;
+
+DROP TRIGGER record_changed;
+CREATE TRIGGER record_changed AFTER UPDATE OF unix_date, amount_indivisible, spend_group, revoked,
+                                              note, currency, amount_base, tags ON spending_records BEGIN
+    UPDATE change_counter SET value = value + 1;
+    UPDATE spending_records SET changed = (SELECT value FROM change_counter) WHERE rowid = NEW.rowid;
+END;
+";
+
+// Monthly spending limits of each principal, in base currency minor units; `category` covers its
+// subcategories too, and NULL one stands for all expenses.
+const BUDGETS_V1: &str = "
+CREATE TABLE budgets (
+    principal  TEXT DEFAULT NULL,
+    category   TEXT DEFAULT NULL,
+    limit_base INT  NOT NULL
+);
+CREATE UNIQUE INDEX budgets_of ON budgets(ifnull(principal, ''), ifnull(category, ''));
+";
+
+
+#[cfg(feature = "server")]
+pub const SERVER_MIGRATIONS: &[&str] = &[
+    SPENDING_RECORDS_V1,
+    USERS_V1,
+    AMENDMENTS_V1,
+    NOTES_V1,
+    CURRENCIES_V1,
+    MINOR_UNITS_V1,
+    TEMP_ALIASES_V1,
+    CHANGE_CURSOR_V1,
+    CATEGORIES_V1,
+    DEFAULT_CATEGORIES_V1,
+    TAGS_V1,
+    BUDGETS_V1,
+];
+
+// Also built for server tests, which synchronize a self-hosted database with it.
+#[cfg(any(feature = "selfhost", test))]
+pub const SELFHOST_MIGRATIONS: &[&str] = &[
+    SPENDING_RECORDS_V1,
+    AMENDMENTS_V1,
+    NOTES_V1,
+    CURRENCIES_V1,
+    MINOR_UNITS_V1,
+    TEMP_ALIASES_V1,
+    CHANGE_CURSOR_V1,
+    SYNC_PEERS_V1,
+    CATEGORIES_V1,
+    SELFHOST_DEFAULT_CATEGORIES_V1,
+    TAGS_V1,
+    BUDGETS_V1,
+];
+
+
+pub fn schema_version(conn: &Connection) -> Result<usize> {
+    let v: i64 = conn.query_row("PRAGMA user_version;", (), |row| row.get(0))?;
+    Ok(v as usize)
+}
+
+/// Brings the database up to the latest version in `steps`, applying each pending step in its
+/// own transaction. Refuses to touch files written by a newer build.
+pub fn migrate(conn: &mut Connection, steps: &[&str]) -> Result<()> {
+    let current = schema_version(conn)?;
+    ensure!(current <= steps.len(),
+            "database schema version {current} is newer than supported {}", steps.len());
+
+    for (version, step) in steps.iter().enumerate().skip(current) {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+        tx.execute_batch(step)
+          .with_context(|| format!("migration to schema version {} failed", version + 1))?;
+        // PRAGMA does not accept bound parameters.
+        tx.execute_batch(&format!("PRAGMA user_version = {};", version + 1))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
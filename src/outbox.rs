@@ -0,0 +1,367 @@
+// #[sides(client#not-selfhost)]
+
+use serde::{Deserialize, Serialize};
+use postcard::{to_stdvec, from_bytes};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::crosstyping::*;
+use crate::db_slice::now;
+
+
+/// Update waiting to be sent, or (for expenses) to be confirmed by server.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Queued {
+    pub key: Uuid,    // `temp_alias` for expenses
+    pub update: ServerboundUpdate,
+    pub queued_at: OffsetDateTime,
+}
+
+/// How `Queued` is encoded by this build; older encodings are still read, see `v1` and `v2`.
+const ENCODING: i64 = 3;
+
+/// Reads an entry persisted in `version` encoding.
+#[cfg(not(target_arch = "wasm32"))]
+fn decode(version: i64, bytes: &[u8]) -> Option<Queued> {
+    match version {
+        1 => from_bytes::<v1::Queued>(bytes).ok().map(|q| v2::Queued::from(q).into()),
+        2 => from_bytes::<v2::Queued>(bytes).ok().map(Into::into),
+        ENCODING => from_bytes(bytes).ok(),
+        _ => None,
+    }
+}
+
+/// Entries as persisted before expenses had tags.
+mod v1 {
+    use serde::Deserialize;
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+    
+    use super::v2;
+    use crate::crosstyping::{Category, ClientData};
+    
+    #[derive(Deserialize)]
+    struct Info {
+        amount: u64,
+        group: Option<String>,
+        revoked: bool,
+        note: Option<String>,
+        currency: String,
+    }
+    impl From<Info> for ClientData {
+        fn from(i: Info) -> Self {
+            ClientData {amount: i.amount, group: i.group, revoked: i.revoked, note: i.note,
+                        currency: i.currency, tags: vec![]}
+        }
+    }
+    
+    #[derive(Deserialize)]
+    enum Update {
+        Revoked {expense_id: Uuid},
+        MadeExpense {info: Info, temp_alias: Uuid},
+        Amend {expense_id: Uuid, info: Info, time: OffsetDateTime},
+        QueryHistory {before: OffsetDateTime, amount: usize},
+        SetExchangeRate {currency: String, base_per_unit: f64},
+        SetCategories {categories: Vec<Category>},
+    }
+    impl From<Update> for v2::Update {
+        fn from(u: Update) -> Self {
+            match u {
+                Update::Revoked{expense_id} => v2::Update::Revoked{expense_id},
+                Update::MadeExpense{info, temp_alias} => v2::Update::MadeExpense{info: info.into(), temp_alias},
+                Update::Amend{expense_id, info, time} => v2::Update::Amend{expense_id, info: info.into(), time},
+                Update::QueryHistory{before, amount} =>
+                    v2::Update::QueryHistory{before: (before, Uuid::max()), amount},
+                Update::SetExchangeRate{currency, base_per_unit} =>
+                    v2::Update::SetExchangeRate{currency, base_per_unit},
+                Update::SetCategories{categories} => v2::Update::SetCategories{categories},
+            }
+        }
+    }
+    
+    #[derive(Deserialize)]
+    pub struct Queued {
+        key: Uuid,
+        update: Update,
+        queued_at: OffsetDateTime,
+    }
+    impl From<Queued> for v2::Queued {
+        fn from(q: Queued) -> Self {
+            v2::Queued {key: q.key, update: q.update.into(), queued_at: q.queued_at}
+        }
+    }
+}
+
+/// Entries as persisted before expenses carried the time they were made; it is taken to be when
+/// they were queued.
+mod v2 {
+    use serde::Deserialize;
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+    
+    use crate::crosstyping::{Budget, Category, ClientData, ServerboundUpdate};
+    
+    #[derive(Deserialize)]
+    pub enum Update {
+        Revoked {expense_id: Uuid},
+        MadeExpense {info: ClientData, temp_alias: Uuid},
+        Amend {expense_id: Uuid, info: ClientData, time: OffsetDateTime},
+        QueryHistory {before: (OffsetDateTime, Uuid), amount: usize},
+        SetExchangeRate {currency: String, base_per_unit: f64},
+        SetCategories {categories: Vec<Category>},
+        QueryTagged {tag: String, before: OffsetDateTime, amount: usize},
+        SetBudgets {budgets: Vec<Budget>},
+        QueryPeriod {from: Option<OffsetDateTime>, to: Option<OffsetDateTime>},
+        Restore {expense_id: Uuid},
+    }
+    
+    #[derive(Deserialize)]
+    pub struct Queued {
+        pub key: Uuid,
+        pub update: Update,
+        pub queued_at: OffsetDateTime,
+    }
+    impl From<Queued> for super::Queued {
+        fn from(q: Queued) -> Self {
+            let update = match q.update {
+                Update::Revoked{expense_id} => ServerboundUpdate::Revoked{expense_id},
+                Update::MadeExpense{info, temp_alias} =>
+                    ServerboundUpdate::MadeExpense{info, temp_alias, time: q.queued_at},
+                Update::Amend{expense_id, info, time} => ServerboundUpdate::Amend{expense_id, info, time},
+                Update::QueryHistory{before, amount} => ServerboundUpdate::QueryHistory{before, amount},
+                Update::SetExchangeRate{currency, base_per_unit} =>
+                    ServerboundUpdate::SetExchangeRate{currency, base_per_unit},
+                Update::SetCategories{categories} => ServerboundUpdate::SetCategories{categories},
+                Update::QueryTagged{tag, before, amount} => ServerboundUpdate::QueryTagged{tag, before, amount},
+                Update::SetBudgets{budgets} => ServerboundUpdate::SetBudgets{budgets},
+                Update::QueryPeriod{from, to} => ServerboundUpdate::QueryPeriod{from, to},
+                Update::Restore{expense_id} => ServerboundUpdate::Restore{expense_id},
+            };
+            super::Queued {key: q.key, update, queued_at: q.queued_at}
+        }
+    }
+}
+
+
+/// Updates not yet delivered to the server, persisted so that they survive restarts.
+///
+/// Expenses are kept until the server confirms them, since they may be resubmitted safely. Other
+/// updates are kept only until sent; history queries are never kept.
+pub struct Outbox {
+    entries: Vec<Queued>,
+    store: Store,
+}
+
+impl Outbox {
+    /// Loads updates left from earlier runs under `scope`, which tells apart servers and users.
+    pub fn open(scope: &str) -> Self {
+        let store = Store::open(scope);
+        let entries = store.load();
+        Self {entries, store}
+    }
+    
+    /// Remembers an update, returning its key unless it is not worth keeping.
+    pub fn push(&mut self, update: ServerboundUpdate) -> Option<Uuid> {
+        let key = match &update {
+            ServerboundUpdate::QueryHistory{..} | ServerboundUpdate::QueryTagged{..} |
+            ServerboundUpdate::QueryPeriod{..} => return None,
+            ServerboundUpdate::MadeExpense{temp_alias, ..} => *temp_alias,
+            _ => Uuid::new_v4(),
+        };
+        self.entries.push(Queued {key, update, queued_at: now()});
+        self.store.put(&self.entries);
+        Some(key)
+    }
+    
+    /// Forgets an update which is sent, or an expense which is confirmed.
+    pub fn remove(&mut self, key: Uuid) {
+        if let Some(i) = self.entries.iter().position(|q| q.key == key) {
+            self.entries.remove(i);
+            self.store.remove(key, &self.entries);
+        }
+    }
+    
+    /// Call once update under `key` was sent; expenses stay until confirmed.
+    pub fn sent(&mut self, key: Uuid) {
+        let expense = self.entries.iter()
+            .any(|q| q.key == key && matches!(q.update, ServerboundUpdate::MadeExpense{..}));
+        if !expense {
+            self.remove(key);
+        }
+    }
+    
+    pub fn entries(&self) -> Vec<Queued> {
+        self.entries.clone()
+    }
+    
+    /// Expenses not yet confirmed, to be shown as provisional ones.
+    pub fn pending_expenses(&self) -> Vec<(Uuid, ClientData, OffsetDateTime)> {
+        self.entries.iter()
+            .filter_map(|q| match &q.update {
+                ServerboundUpdate::MadeExpense{info, temp_alias, time} => Some((*temp_alias, info.clone(), *time)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+
+// Native client keeps the outbox in a SQLite file next to other app data.
+#[cfg(not(target_arch = "wasm32"))]
+struct Store {
+    conn: rusqlite::Connection,
+    scope: String,
+}
+#[cfg(not(target_arch = "wasm32"))]
+impl Store {
+    fn open(scope: &str) -> Self {
+        Self {conn: Self::open_database(), scope: scope.to_owned()}
+    }
+    
+    fn open_database() -> rusqlite::Connection {
+        let path = eframe::storage_dir(crate::graphics::APP_ID).map(|dir| dir.join("outbox.sqlite3"));
+        let conn = path.and_then(|path| {
+            std::fs::create_dir_all(path.parent()?).ok()?;
+            rusqlite::Connection::open(path).inspect_err(|e| eprintln!("Outbox is not persisted: {e}")).ok()
+        });
+        let conn = conn.unwrap_or_else(|| rusqlite::Connection::open_in_memory().unwrap());
+        conn.execute_batch("
+CREATE TABLE IF NOT EXISTS outbox (
+    seq    INTEGER PRIMARY KEY AUTOINCREMENT,
+    scope  TEXT NOT NULL,
+    key    BLOB NOT NULL,
+    queued BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS outbox_of ON outbox(scope, key);
+CREATE TABLE IF NOT EXISTS principals (
+    server    TEXT NOT NULL,
+    device    TEXT NOT NULL,
+    principal TEXT NOT NULL,
+    PRIMARY KEY(server, device)
+);
+        ").unwrap();
+        let versioned: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('outbox') WHERE name = 'version'", (), |row| row.get(0)
+        ).unwrap();
+        if !versioned {
+            // Rows written before encodings were told apart are all in the first one.
+            conn.execute_batch("ALTER TABLE outbox ADD COLUMN version INTEGER NOT NULL DEFAULT 1;").unwrap();
+        }
+        conn
+    }
+    
+    /// Entries which cannot be read are reported and left in place, so that they are not lost.
+    fn load(&self) -> Vec<Queued> {
+        let mut stmt = self.conn.prepare("SELECT seq, version, queued FROM outbox WHERE scope = ?1 ORDER BY seq")
+            .unwrap();
+        stmt.query_map((&self.scope,), |row| Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get::<_, Vec<u8>>(2)?)))
+            .unwrap()
+            .filter_map(|r| {
+                let (seq, version, bytes) = r.inspect_err(|e| eprintln!("Outbox entry is not read: {e}")).ok()?;
+                let queued = decode(version, &bytes);
+                if queued.is_none() {
+                    eprintln!("Outbox entry {seq} in encoding {version} cannot be read; it is kept as is");
+                }
+                queued
+            })
+            .collect()
+    }
+    
+    fn put(&mut self, entries: &[Queued]) {
+        let queued = entries.last().unwrap();
+        let bytes = to_stdvec(queued).unwrap();
+        if let Err(e) = self.conn.execute("INSERT INTO outbox(scope, key, queued, version) VALUES(?1, ?2, ?3, ?4)",
+                                          (&self.scope, queued.key, bytes, ENCODING)) {
+            eprintln!("Outbox is not persisted: {e}");
+        }
+    }
+    
+    fn remove(&mut self, key: Uuid, _entries: &[Queued]) {
+        if let Err(e) = self.conn.execute("DELETE FROM outbox WHERE scope = ?1 AND key = ?2",
+                                          (&self.scope, key)) {
+            eprintln!("Outbox is not persisted: {e}");
+        }
+    }
+}
+
+
+/// Remembers whom `device` signs in as on the server, so that its outbox is found while offline.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn remember_principal(api_base: &str, device: &str, principal: &str) {
+    if let Err(e) = Store::open_database().execute(
+            "INSERT OR REPLACE INTO principals(server, device, principal) VALUES(?1, ?2, ?3)",
+            (api_base, device, principal)) {
+        eprintln!("Outbox is not persisted: {e}");
+    }
+}
+
+/// Whom `device` signed in as on the server last time.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn known_principal(api_base: &str, device: &str) -> Option<String> {
+    use rusqlite::OptionalExtension;
+    
+    Store::open_database()
+        .query_row("SELECT principal FROM principals WHERE server = ?1 AND device = ?2", (api_base, device),
+                   |row| row.get(0))
+        .optional()
+        .inspect_err(|e| eprintln!("Outbox is not read: {e}"))
+        .ok()
+        .flatten()
+}
+
+
+// Browser keeps the whole outbox as one localStorage item, rewritten on every change. The item is
+// hex-encoded entries, prefixed with their encoding and `:` since the first one.
+#[cfg(target_arch = "wasm32")]
+struct Store {
+    item: String,
+}
+#[cfg(target_arch = "wasm32")]
+impl Store {
+    fn open(scope: &str) -> Self {
+        Self {item: format!("tea-outbox:{scope}")}
+    }
+    
+    fn storage() -> Option<web_sys::Storage> {
+        web_sys::window()?.local_storage().ok()?
+    }
+    
+    /// Entries which cannot be read are reported and set aside, so that saving does not lose them.
+    fn load(&self) -> Vec<Queued> {
+        let Some(storage) = Self::storage() else {return vec![]};
+        let Some(text) = storage.get_item(&self.item).ok().flatten() else {return vec![]};
+        let (version, encoded) = match text.split_once(':') {
+            Some((version, encoded)) => (version.parse().unwrap_or(0), encoded),
+            None => (1, text.as_str()),
+        };
+        let entries = hex::decode(encoded).ok().and_then(|bytes| match version {
+            1 => from_bytes::<Vec<v1::Queued>>(&bytes).ok()
+                .map(|v| v.into_iter().map(|q| v2::Queued::from(q).into()).collect()),
+            2 => from_bytes::<Vec<v2::Queued>>(&bytes).ok().map(|v| v.into_iter().map(Into::into).collect()),
+            ENCODING => from_bytes(&bytes).ok(),
+            _ => None,
+        });
+        entries.unwrap_or_else(|| {
+            let aside = format!("{}:unread", self.item);
+            log::error!("Outbox in encoding {version} cannot be read; it is kept as {aside}");
+            let _ = storage.set_item(&aside, &text);
+            vec![]
+        })
+    }
+    
+    fn save(&self, entries: &[Queued]) {
+        let Some(storage) = Self::storage() else {return};
+        let _ = match entries {
+            [] => storage.remove_item(&self.item),
+            _  => storage.set_item(&self.item, &format!("{ENCODING}:{}", hex::encode(to_stdvec(entries).unwrap()))),
+        };
+    }
+    
+    fn put(&mut self, entries: &[Queued]) {
+        self.save(entries);
+    }
+    
+    fn remove(&mut self, _key: Uuid, entries: &[Queued]) {
+        self.save(entries);
+    }
+}
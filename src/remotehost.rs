@@ -1,6 +1,6 @@
 // #[sides(client#not-selfhost)]
 
-use reqwest::{cookie::{Jar, CookieStore}, Client, Response};
+use reqwest::{cookie::{Jar, CookieStore}, Client};
 use tokio_tungstenite::{connect_async, WebSocketStream};
 use tungstenite::ClientRequestBuilder;
 use postcard::{to_stdvec, from_bytes};
@@ -8,81 +8,299 @@ use futures::{SinkExt, StreamExt};
 use tokio::sync::{mpsc, oneshot};
 use totp_rs::{Algorithm, TOTP};
 use tungstenite::Message;
+use anyhow::{ensure, Context, Result};
+use time::OffsetDateTime;
+use std::time::Duration;
 use std::sync::Arc;
+use uuid::Uuid;
 
+use crate::keystore::Credentials;
+use crate::outbox::{known_principal, remember_principal, Outbox};
 use crate::crosstyping::*;
 
 
 type MayTls = tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>;
+
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+
+/// Background task's side of `RemoteDatabase`, outliving any single connection.
+struct Link {
+    up_rx: mpsc::UnboundedReceiver<ServerboundUpdate>,
+    down_tx: mpsc::UnboundedSender<ClientboundUpdate>,
+    init_data_tx: Option<oneshot::Sender<(CachedStats, CachedStats, Vec<Expense>)>>,
+    outbox: Outbox,
+}
+
+enum Ended {
+    Connection,
+    Application,
+}
+
+/// What reopening the stream takes: the session cookie, or credentials to log in anew.
+enum Session {
+    Cookie(String),
+    Credentials(Credentials),
+}
+
+/// Whether the server could not be reached at all, rather than refused us.
+fn unreachable(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause.downcast_ref::<reqwest::Error>().is_some_and(|e| e.is_connect() || e.is_timeout()) ||
+            matches!(cause.downcast_ref::<tungstenite::Error>(), Some(tungstenite::Error::Io(_)))
+    })
+}
+
 pub struct RemoteDatabase {
     up: mpsc::UnboundedSender<ServerboundUpdate>,
     down: mpsc::UnboundedReceiver<ClientboundUpdate>,
     init_data: Option<(CachedStats, CachedStats, Vec<Expense>)>,
+    pending: Vec<(Uuid, ClientData, OffsetDateTime)>,
+    api_base: String,
+    // Replaced on signing in again; empty until the server is first reached.
+    cookie: Arc<std::sync::Mutex<String>>,
+    // Downloads are started from GUI thread, which is not within the runtime.
+    runtime: tokio::runtime::Handle,
 }
 impl RemoteDatabase {
-    async fn login(api_base: &str, device: &str, secret: Vec<u8>) -> (Response, Arc<Jar>) {
-        let totp = TOTP::new(Algorithm::SHA1, 8, 1, 20, secret).unwrap();
-        let code = totp.generate_current().unwrap();
-        
+    /// Posts to one of `/api/login`, `/api/register` endpoints, returning the session cookie and
+    /// reply body, or the reason server gave for refusal.
+    async fn authenticate(api_base: &str, endpoint: &str, device: &str, body: String)
+            -> Result<(String, Vec<u8>)> {
         let jar = Arc::new(Jar::default());
-        let path = api_base.to_owned() + "/api/login/" + device;
-        let client = Client::builder().cookie_provider(jar.clone()).build().unwrap();
-        let response = client.post(path).body(code).send().await.unwrap();
-        (response.error_for_status().unwrap(), jar)
+        let path = format!("{api_base}/api/{endpoint}/{device}");
+        let url: reqwest::Url = path.parse().with_context(|| format!("invalid server address {api_base}"))?;
+        let client = Client::builder().cookie_provider(jar.clone()).build()?;
+        let response = client.post(url.clone()).body(body).send().await
+            .with_context(|| format!("server {api_base} is unreachable"))?;
+        
+        let status = response.status();
+        let reply = response.bytes().await?.to_vec();
+        ensure!(status.is_success(), "{}", String::from_utf8_lossy(&reply));
+        
+        let cookie = jar.cookies(&url).context("server did not start a session")?;
+        Ok((cookie.to_str()?.to_owned(), reply))
     }
     
-    async fn serve(mut conn: WebSocketStream<MayTls>) -> Self {
-        let (up, mut up_rx) = mpsc::unbounded_channel();
-        let (down_tx, down) = mpsc::unbounded_channel();
-        let (init_data_tx, init_data) = oneshot::channel();
-        let mut init_data_tx = Some(init_data_tx);
-        
-        tokio::task::spawn(async move {
-            loop {tokio::select!{
-                msg_result = conn.next() => {
-                    let Some(Ok(msg)) = msg_result else {return};
-                    let Message::Binary(m) = msg else {continue};
-                    let Ok(inbound): Result<ClientboundUpdate, _> = from_bytes(&m) else {return};
-                    
-                    match inbound {
-                        ClientboundUpdate::InitStats{lifetime_stats, recent_expenses} => {
-                            let Some(init_data_tx) = init_data_tx.take() else {continue};
-                            
-                            // we will calculate stats on this thread, not on GUI one
-                            let lifetime_stats = CachedStats::new(lifetime_stats);
-                            let mut month_stats = CachedStats::default();
-                            recent_expenses.iter().for_each(|e| month_stats.add(e));
-                            let _ = init_data_tx.send((lifetime_stats, month_stats, recent_expenses));
-                        },
-                        i => {
-                            if let Err(_) = down_tx.send(i) {return;}
+    /// Logs in with a code from authenticator, returning the session cookie.
+    pub async fn login(api_base: &str, device: &str, code: &str) -> Result<String> {
+        let (cookie, _) = Self::authenticate(api_base, "login", device, code.trim().to_owned()).await?;
+        Ok(cookie)
+    }
+    
+    /// Registers a new device for a new principal, returning the session cookie and TOTP secret.
+    pub async fn register(api_base: &str, device: &str, principal: &str) -> Result<(String, Vec<u8>)> {
+        Self::authenticate(api_base, "register", device, principal.to_owned()).await
+    }
+    
+    /// Asks whom the session cookie belongs to.
+    async fn whoami(api_base: &str, cookie: &str) -> Result<String> {
+        let response = Client::new().get(format!("{api_base}/api/me")).header("Cookie", cookie)
+            .send().await.with_context(|| format!("server {api_base} is unreachable"))?;
+        let principal = response.error_for_status()?.text().await?;
+        ensure!(!principal.is_empty(), "server did not recognize the session");
+        Ok(principal)
+    }
+    
+    /// Relays updates between the socket and `DbView` until either side is gone.
+    async fn pump(conn: &mut WebSocketStream<MayTls>, link: &mut Link) -> Ended {
+        loop {tokio::select!{
+            msg_result = conn.next() => {
+                let Some(Ok(msg)) = msg_result else {return Ended::Connection};
+                let Message::Binary(m) = msg else {continue};
+                let Ok(inbound): Result<ClientboundUpdate, _> = from_bytes(&m) else {return Ended::Connection};
+                
+                match inbound {
+                    ClientboundUpdate::InitStats{lifetime_stats, recent_expenses, categories}
+                            if link.init_data_tx.is_some() => {
+                        let init_data_tx = link.init_data_tx.take().unwrap();
+                        
+                        // we will calculate stats on this thread, not on GUI one
+                        let lifetime_stats = CachedStats::new(lifetime_stats);
+                        let mut month_stats = CachedStats::default();
+                        recent_expenses.iter().for_each(|e| month_stats.add(e));
+                        let _ = init_data_tx.send((lifetime_stats, month_stats, recent_expenses));
+                        // Those go along with other updates, as initial data carries none.
+                        let categories = ClientboundUpdate::Categories{categories};
+                        if link.down_tx.send(categories).is_err() {return Ended::Application;}
+                    },
+                    i => {
+                        if let ClientboundUpdate::NewSpending{temp_alias, ..} | ClientboundUpdate::Rejected{
+                                    update: ServerboundUpdate::MadeExpense{temp_alias, ..}, ..} = &i {
+                            link.outbox.remove(*temp_alias);
                         }
+                        if link.down_tx.send(i).is_err() {return Ended::Application;}
                     }
+                }
+            },
+            up_query = link.up_rx.recv() => {
+                let Some(up_query) = up_query else {return Ended::Application};
+                let b = Message::Binary(to_stdvec(&up_query).unwrap().into());
+                let key = link.outbox.push(up_query);
+                if conn.send(b).await.is_err() {return Ended::Connection;}
+                if let Some(key) = key {
+                    link.outbox.sent(key);
+                }
+            }
+        }}
+    }
+    
+    /// Sends everything in the outbox, oldest first.
+    async fn flush(conn: &mut WebSocketStream<MayTls>, outbox: &mut Outbox) -> Result<(), tungstenite::Error> {
+        for queued in outbox.entries() {
+            conn.send(Message::Binary(to_stdvec(&queued.update).unwrap().into())).await?;
+            outbox.sent(queued.key);
+        }
+        Ok(())
+    }
+    
+    async fn open_stream(ws_url: &str, cookie: &str) -> Result<WebSocketStream<MayTls>> {
+        let builder = ClientRequestBuilder::new(ws_url.parse()?).with_header("Cookie", cookie);
+        let (conn, _response) = connect_async(builder).await
+            .with_context(|| format!("cannot open update stream at {ws_url}"))?;
+        Ok(conn)
+    }
+    
+    /// Returns the session cookie, logging in if the session is not established yet.
+    async fn session_cookie(api_base: &str, session: &Session) -> Result<String> {
+        match session {
+            Session::Cookie(cookie) => Ok(cookie.clone()),
+            Session::Credentials(credentials) => {
+                let totp = TOTP::new(Algorithm::SHA1, 8, 1, 20, credentials.secret.clone())?;
+                let code = totp.generate_current()?;
+                Self::login(api_base, &credentials.device, &code).await
+            },
+        }
+    }
+    
+    /// Reopens the stream, signing in again if the session allows, waiting longer after each
+    /// failure and keeping whatever is submitted meanwhile in the outbox.
+    /// Returns the session cookie and the stream, or `None` once there is nobody to deliver
+    /// updates to.
+    async fn reconnect(api_base: &str, session: &Session, link: &mut Link)
+            -> Option<(String, WebSocketStream<MayTls>)> {
+        let ws_url = api_base.replacen("http", "ws", 1) + "/ws";
+        let mut delay = RECONNECT_DELAY_MIN;
+        loop {
+            let sleep = tokio::time::sleep(delay);
+            tokio::pin!(sleep);
+            loop {tokio::select!{
+                _ = &mut sleep => break,
+                _ = link.down_tx.closed() => return None,
+                up_query = link.up_rx.recv() => {
+                    link.outbox.push(up_query?);
                 },
-                up_query = up_rx.recv() => {
-                    let Some(up_query) = up_query else {return};
-                    let b = Message::Binary(to_stdvec(&up_query).unwrap().into());
-                    if let Err(_) = conn.send(b).await {return;}
+            }}
+            let reopened = async {
+                let cookie = Self::session_cookie(api_base, session).await?;
+                let conn = Self::open_stream(&ws_url, &cookie).await?;
+                anyhow::Ok((cookie, conn))
+            };
+            match reopened.await {
+                Ok(reopened) => return Some(reopened),
+                Err(e) => eprintln!("Reconnection failed: {e:#}"),
+            }
+            delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+        }
+    }
+    
+    /// Relays updates in background; without a stream yet, starts from reconnecting.
+    async fn serve(api_base: String, session: Session, cookie: String, outbox: Outbox,
+                   mut conn: Option<WebSocketStream<MayTls>>) -> Self {
+        let (up, up_rx) = mpsc::unbounded_channel();
+        let (down_tx, down) = mpsc::unbounded_channel();
+        // Offline, initial data comes along with other updates once the server is reached.
+        let (init_data_tx, init_data) = match conn {
+            Some(_) => {
+                let (init_data_tx, init_data) = oneshot::channel();
+                (Some(init_data_tx), Some(init_data))
+            },
+            None => (None, None),
+        };
+        let pending = outbox.pending_expenses();
+        let cookie = Arc::new(std::sync::Mutex::new(cookie));
+        let mut link = Link {
+            up_rx,
+            down_tx,
+            init_data_tx,
+            outbox,
+        };
+        
+        tokio::task::spawn({
+            let api_base = api_base.clone();
+            let cookie = cookie.clone();
+            async move {loop {
+                // Server sends fresh `InitStats` on its own; expenses it might have missed, maybe
+                // in earlier runs, are submitted again along with whatever was queued meanwhile.
+                if let Some(conn) = &mut conn {
+                    if Self::flush(conn, &mut link.outbox).await.is_ok() {
+                        if let Ended::Application = Self::pump(conn, &mut link).await {return}
+                    }
                 }
+                let Some((new_cookie, new_conn)) = Self::reconnect(&api_base, &session, &mut link).await else {
+                    return
+                };
+                *cookie.lock().unwrap() = new_cookie;
+                conn = Some(new_conn);
             }}
         });
-        let init_data = init_data.await.ok();
+        let init_data = match init_data {
+            Some(init_data) => init_data.await.ok(),
+            None => None,
+        };
         
-        Self {up, down, init_data}
+        Self {up, down, init_data, pending, api_base, cookie, runtime: tokio::runtime::Handle::current()}
     }
     
-    pub async fn connect(api_base: &str, credential: (&str, Vec<u8>)) -> Self {
-        let path = api_base.to_owned() + "/ws";
-        let rq_path = path.parse().unwrap();
-        let tt_path = path.replacen("http", "ws", 1).parse().unwrap();
-        
-        let (_, auth_response) = Self::login(api_base, credential.0, credential.1).await;
-        let cookie = auth_response.cookies(&rq_path).expect("need cookie");
-        let cookie_str = cookie.to_str().unwrap();
-        let builder = ClientRequestBuilder::new(tt_path).with_header("Cookie", cookie_str);
-        let (conn, _response) = connect_async(builder).await.unwrap();
+    /// Opens the update stream, which is reopened whenever connection drops. Updates which could
+    /// not be delivered are kept until then, even across restarts.
+    /// If the server is unreachable, `device` works offline as the principal it was last time.
+    async fn start(api_base: &str, device: &str, session: Session) -> Result<Self> {
+        let ws_url = api_base.replacen("http", "ws", 1) + "/ws";
+        let online = async {
+            let cookie = Self::session_cookie(api_base, &session).await?;
+            let principal = Self::whoami(api_base, &cookie).await?;
+            let conn = Self::open_stream(&ws_url, &cookie).await?;
+            anyhow::Ok((cookie, principal, conn))
+        };
+        let (cookie, principal, conn) = match online.await {
+            Ok((cookie, principal, conn)) => {
+                remember_principal(api_base, device, &principal);
+                (cookie, principal, Some(conn))
+            },
+            Err(e) if unreachable(&e) => {
+                let principal = known_principal(api_base, device).ok_or(e)?;
+                (String::new(), principal, None)
+            },
+            Err(e) => return Err(e),
+        };
+        let outbox = Outbox::open(&format!("{principal}@{api_base}"));
+        Ok(Self::serve(api_base.to_owned(), session, cookie, outbox, conn).await)
+    }
+    
+    /// Opens the update stream of an already established session of `device`.
+    pub async fn open(api_base: &str, device: &str, cookie: &str) -> Result<Self> {
+        Self::start(api_base, device, Session::Cookie(cookie.to_owned())).await
+    }
+    
+    /// Downloads `/api/export` into `path`.
+    async fn download_export(url: String, cookie: String, path: std::path::PathBuf) -> Result<String> {
+        let mut response = Client::new().get(url).header("Cookie", cookie).send().await
+            .context("server is unreachable")?;
+        ensure!(response.status().is_success(), "{}", response.text().await?);
         
-        Self::serve(conn).await
+        let mut file = std::fs::File::create(&path)
+            .with_context(|| format!("cannot write {}", path.display()))?;
+        while let Some(chunk) = response.chunk().await? {
+            std::io::Write::write_all(&mut file, &chunk)?;
+        }
+        Ok(path.display().to_string())
+    }
+    
+    /// Logs in with a code generated from the device secret, then opens the update stream;
+    /// reconnections log in the same way.
+    pub async fn connect(credentials: &Credentials) -> Result<Self> {
+        Self::start(&credentials.url, &credentials.device, Session::Credentials(credentials.clone())).await
     }
 }
 impl Upstream for RemoteDatabase {
@@ -98,5 +316,18 @@ impl Upstream for RemoteDatabase {
     fn take_init(&mut self) -> Option<(CachedStats, CachedStats, Vec<Expense>)> {
         self.init_data.take()
     }
+    fn take_pending(&mut self) -> Vec<(Uuid, ClientData, OffsetDateTime)> {
+        std::mem::take(&mut self.pending)
+    }
+    fn export(&mut self, format: ExportFormat, include_revoked: bool) -> oneshot::Receiver<Result<String, String>> {
+        let url = format!("{}/api/export?format={}&revoked={include_revoked}", self.api_base, format.query_name());
+        let cookie = self.cookie.lock().unwrap().clone();
+        let download = Self::download_export(url, cookie, crate::graphics::export_path(format));
+        let (tx, rx) = oneshot::channel();
+        self.runtime.spawn(async move {
+            let _ = tx.send(download.await.map_err(|e| format!("{e:#}")));
+        });
+        rx
+    }
 }
 
@@ -2,126 +2,245 @@
 
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
-use web_sys::{Blob, console, CloseEvent, Event, MessageEvent, WebSocket};
+use web_sys::{Blob, console, CloseEvent, Event, MessageEvent, Response, WebSocket};
 use web_sys::js_sys::{ArrayBuffer, Uint8Array};
+use wasm_bindgen_futures::JsFuture;
 use postcard::{to_stdvec, from_bytes};
-use futures::channel::mpsc;
-use std::sync::{Arc, Mutex};
+use futures::channel::{mpsc, oneshot};
+use time::OffsetDateTime;
+use std::cell::RefCell;
+use std::rc::Rc;
+use uuid::Uuid;
 
+use crate::outbox::Outbox;
 use crate::crosstyping::*;
 
 
-pub struct RemoteDatabase {
-    up: WebSocket,
-    down: mpsc::UnboundedReceiver<ClientboundUpdate>,
-    init_data: Option<(CachedStats, CachedStats, Vec<Expense>)>,
+const RECONNECT_DELAY_MIN_MS: i32 = 1000;
+const RECONNECT_DELAY_MAX_MS: i32 = 60_000;
+
+/// State shared by socket callbacks, outliving any single socket.
+struct Link {
+    ws: WebSocket,
+    ws_url: String,
+    down_tx: mpsc::UnboundedSender<ClientboundUpdate>,
+    init_data_tx: Option<oneshot::Sender<(CachedStats, CachedStats, Vec<Expense>)>>,
+    outbox: Outbox,
+    reconnect_delay_ms: i32,
+    // Set right after the link is shared, as they hold it.
+    callbacks: Option<Callbacks>,
 }
+type SharedLink = Rc<RefCell<Link>>;
 
-impl RemoteDatabase {
-    async fn serve(ws: WebSocket) -> Self {
-        let up = ws.clone();
-        let (down_tx, down_rx) = mpsc::unbounded();
-        let (init_data_tx, init_data_rx) = futures::channel::oneshot::channel();
-        let init_data_tx = Arc::new(Mutex::new(Some(init_data_tx)));
-        
-        // Set up message handler
-        let down_tx_clone = down_tx.clone();
-        let init_tx_clone = init_data_tx.clone();
-        let internal_forwarder = Closure::wrap(Box::new(move |abuf: JsValue| {
-            let Ok(abuf) = abuf.dyn_into::<ArrayBuffer>() else {return};
-            
-            let array = Uint8Array::new(&abuf);
-            let mut buf = vec![0; array.length() as usize];
-            array.copy_to(&mut buf);
-            
-            console::log_1(&array.clone().into());
-            
-            if let Ok(inbound) = from_bytes::<ClientboundUpdate>(&buf) {
-                match inbound {
-                    ClientboundUpdate::InitStats{lifetime_stats, recent_expenses} => {
-                        let mut init_tx_opt = init_tx_clone.lock().unwrap();
-                        if let Some(init_tx) = init_tx_opt.take() {
-                            // Calculate stats on this thread, not on GUI one
-                            let lifetime_stats = CachedStats::new(lifetime_stats);
-                            let mut month_stats = CachedStats::default();
-                            recent_expenses.iter().for_each(|e| month_stats.add(e));
-                            let _ = init_tx.send((lifetime_stats, month_stats, recent_expenses));
-                        }
-                    },
-                    i => {
-                        let _ = down_tx_clone.unbounded_send(i);
-                    }
-                }
+impl Link {
+    fn send(&self, d: &ServerboundUpdate) {
+        if let Ok(binary_data) = to_stdvec(d) {
+            if let Err(_e) = self.ws.send_with_u8_array(&binary_data) {
+                
             }
-        }) as Box<dyn FnMut(JsValue)>);
+        }
+    }
+    
+    fn submit(&mut self, d: ServerboundUpdate) {
+        if self.ws.ready_state() != WebSocket::OPEN {
+            // Sent on reopening, along with the rest of outbox.
+            self.outbox.push(d);
+            return;
+        }
+        self.send(&d);
+        if let Some(key) = self.outbox.push(d) {
+            self.outbox.sent(key);
+        }
+    }
+    
+    fn on_open(&mut self) {
+        self.reconnect_delay_ms = RECONNECT_DELAY_MIN_MS;
         
-        let onmessage_callback = Closure::wrap(Box::new(move |e: MessageEvent| {
-            console::log_1(e.unchecked_ref());
-            
-            if let Ok(blob) = e.data().dyn_into::<Blob>() {
-                let _ = blob.array_buffer().then(&internal_forwarder);
+        // Server sends fresh `InitStats` on its own; expenses it might have missed, maybe
+        // in earlier visits, are submitted again along with whatever was queued meanwhile.
+        for queued in self.outbox.entries() {
+            self.send(&queued.update);
+            self.outbox.sent(queued.key);
+        }
+    }
+    
+    fn on_update(&mut self, inbound: ClientboundUpdate) {
+        match inbound {
+            ClientboundUpdate::InitStats{lifetime_stats, recent_expenses, categories}
+                    if self.init_data_tx.is_some() => {
+                let init_tx = self.init_data_tx.take().unwrap();
+                // Calculate stats on this thread, not on GUI one
+                let lifetime_stats = CachedStats::new(lifetime_stats);
+                let mut month_stats = CachedStats::default();
+                recent_expenses.iter().for_each(|e| month_stats.add(e));
+                let _ = init_tx.send((lifetime_stats, month_stats, recent_expenses));
+                // Those go along with other updates, as initial data carries none.
+                let _ = self.down_tx.unbounded_send(ClientboundUpdate::Categories{categories});
+            },
+            i => {
+                if let ClientboundUpdate::NewSpending{temp_alias, ..} | ClientboundUpdate::Rejected{
+                            update: ServerboundUpdate::MadeExpense{temp_alias, ..}, ..} = &i {
+                    self.outbox.remove(*temp_alias);
+                }
+                let _ = self.down_tx.unbounded_send(i);
             }
-        }) as Box<dyn FnMut(MessageEvent)>);
-        ws.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
-        onmessage_callback.forget();
-        
-        let onerror_callback = Closure::wrap(Box::new(move |e: Event| {
-            console::error_2(&JsValue::from_str("WebSocket error: unknown"),
-                             &e.into());
-        }) as Box<dyn FnMut(Event)>);
-        ws.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
-        onerror_callback.forget();
-        
-        let onclose_callback = Closure::wrap(Box::new(move |e: CloseEvent| {
-            console::error_2(&JsValue::from_str("WebSocket error: connection closed"),
-                             &e.into());
-        }) as Box<dyn FnMut(CloseEvent)>);
-        ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
-        onclose_callback.forget();
+        }
+    }
+}
+
+
+/// Socket callbacks feeding a link. Every socket of the link is given the same ones, so that
+/// reconnecting does not leak new closures.
+struct Callbacks {
+    onmessage: Closure<dyn FnMut(MessageEvent)>,
+    onopen: Closure<dyn FnMut(Event)>,
+    onerror: Closure<dyn FnMut(Event)>,
+    onclose: Closure<dyn FnMut(CloseEvent)>,
+}
+
+fn make_callbacks(link: &SharedLink) -> Callbacks {
+    let forwarder_link = link.clone();
+    let internal_forwarder = Closure::wrap(Box::new(move |abuf: JsValue| {
+        let Ok(abuf) = abuf.dyn_into::<ArrayBuffer>() else {return};
         
-        console::log_1(&JsValue::from_str("Listeners up"));
+        let array = Uint8Array::new(&abuf);
+        let mut buf = vec![0; array.length() as usize];
+        array.copy_to(&mut buf);
         
-        // Wait for initialization data
-        let init_data = match init_data_rx.await {
-            Ok(data) => Some(data),
-            Err(_) => None,
-        };
+        console::log_1(&array.clone().into());
         
-        console::log_1(&JsValue::from_str("Received init data"));
+        if let Ok(inbound) = from_bytes::<ClientboundUpdate>(&buf) {
+            forwarder_link.borrow_mut().on_update(inbound);
+        }
+    }) as Box<dyn FnMut(JsValue)>);
+    
+    let onmessage = Closure::wrap(Box::new(move |e: MessageEvent| {
+        console::log_1(e.unchecked_ref());
         
-        Self { up, down: down_rx, init_data }
-    }
+        if let Ok(blob) = e.data().dyn_into::<Blob>() {
+            let _ = blob.array_buffer().then(&internal_forwarder);
+        }
+    }) as Box<dyn FnMut(MessageEvent)>);
+    
+    let open_link = link.clone();
+    let onopen = Closure::wrap(Box::new(move |_: Event| {
+        console::log_1(&JsValue::from_str("WebSocket opened"));
+        open_link.borrow_mut().on_open();
+    }) as Box<dyn FnMut(Event)>);
+    
+    let onerror = Closure::wrap(Box::new(move |e: Event| {
+        console::error_2(&JsValue::from_str("WebSocket error: unknown"),
+                         &e.into());
+    }) as Box<dyn FnMut(Event)>);
+    
+    let close_link = link.clone();
+    let onclose = Closure::wrap(Box::new(move |e: CloseEvent| {
+        console::error_2(&JsValue::from_str("WebSocket error: connection closed"),
+                         &e.into());
+        schedule_reconnect(&close_link);
+    }) as Box<dyn FnMut(CloseEvent)>);
     
+    Callbacks {onmessage, onopen, onerror, onclose}
+}
+
+/// Points socket callbacks at those of `link`.
+fn attach(link: &Link, ws: &WebSocket) {
+    let callbacks = link.callbacks.as_ref().expect("callbacks are made along with the link");
+    ws.set_onmessage(Some(callbacks.onmessage.as_ref().unchecked_ref()));
+    ws.set_onopen(Some(callbacks.onopen.as_ref().unchecked_ref()));
+    ws.set_onerror(Some(callbacks.onerror.as_ref().unchecked_ref()));
+    ws.set_onclose(Some(callbacks.onclose.as_ref().unchecked_ref()));
+}
+
+/// Opens a new socket after a delay growing with each failure; session cookie is sent by the
+/// browser again. Gives up once there is nobody to deliver updates to.
+fn schedule_reconnect(link: &SharedLink) {
+    let delay = {
+        let mut l = link.borrow_mut();
+        if l.down_tx.is_closed() {return;}
+        let delay = l.reconnect_delay_ms;
+        l.reconnect_delay_ms = (delay * 2).min(RECONNECT_DELAY_MAX_MS);
+        delay
+    };
+    
+    let link = link.clone();
+    let reopen = Closure::once_into_js(move || {
+        let ws_url = link.borrow().ws_url.clone();
+        match WebSocket::new(&ws_url) {
+            Ok(ws) => {
+                attach(&link.borrow(), &ws);
+                link.borrow_mut().ws = ws;
+            },
+            Err(e) => {
+                console::error_2(&JsValue::from_str("WebSocket error: cannot reopen"), &e);
+                schedule_reconnect(&link);
+            },
+        }
+    });
+    let window = web_sys::window().expect("No window");
+    let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(reopen.unchecked_ref(), delay);
+}
+
+
+/// Asks whom the session cookie belongs to; browser sends the cookie on its own.
+async fn whoami(api_base: &str) -> Option<String> {
+    let window = web_sys::window()?;
+    let response = JsFuture::from(window.fetch_with_str(&format!("{api_base}/api/me"))).await.ok()?;
+    let text = JsFuture::from(response.dyn_into::<Response>().ok()?.text().ok()?).await.ok()?;
+    text.as_string().filter(|principal| !principal.is_empty())
+}
+
+
+pub struct RemoteDatabase {
+    link: SharedLink,
+    down: mpsc::UnboundedReceiver<ClientboundUpdate>,
+    init_data: Option<(CachedStats, CachedStats, Vec<Expense>)>,
+    pending: Vec<(Uuid, ClientData, OffsetDateTime)>,
+    api_base: String,
+}
+
+impl RemoteDatabase {
     pub async fn connect(api_base: &str, _credential: ()) -> Self {
         // We assume we are logged in already.
         
+        let principal = whoami(api_base).await.unwrap_or_default();
+        let outbox = Outbox::open(&format!("{principal}@{api_base}"));
+        let pending = outbox.pending_expenses();
+        
         let ws_url = api_base.replacen("http", "ws", 1) + "/ws";
         let ws = WebSocket::new(&ws_url).unwrap();
         
         console::log_1(&JsValue::from_str("WebSocket scheduled"));
         
-        let (ready_tx, ready_rx) = futures::channel::oneshot::channel::<()>();
-        let onopen_callback = Closure::once_into_js(move |_: web_sys::Event| {
-            // An error would mean that Self::connect is cancelled, like if exitting application.
-            // Also, this is an event listener, there is nothing else to do if notification fails.
-            let _ = ready_tx.send(());
-        });
-        ws.set_onopen(Some(onopen_callback.unchecked_ref()));
-        let _ = ready_rx.await;
+        let (down_tx, down) = mpsc::unbounded();
+        let (init_data_tx, init_data_rx) = oneshot::channel();
+        let link = Rc::new(RefCell::new(Link {
+            ws: ws.clone(),
+            ws_url,
+            down_tx,
+            init_data_tx: Some(init_data_tx),
+            outbox,
+            reconnect_delay_ms: RECONNECT_DELAY_MIN_MS,
+            callbacks: None,
+        }));
+        let callbacks = make_callbacks(&link);
+        link.borrow_mut().callbacks = Some(callbacks);
+        attach(&link.borrow(), &ws);
         
-        console::log_1(&JsValue::from_str("WebSocket opened"));
+        console::log_1(&JsValue::from_str("Listeners up"));
+        
+        // Wait for initialization data, surviving reconnections if any
+        let init_data = init_data_rx.await.ok();
+        
+        console::log_1(&JsValue::from_str("Received init data"));
         
-        Self::serve(ws).await
+        Self { link, down, init_data, pending, api_base: api_base.to_owned() }
     }
 }
 
 impl Upstream for RemoteDatabase {
     fn submit(&mut self, d: ServerboundUpdate) {
-        if let Ok(binary_data) = to_stdvec(&d) {
-            if let Err(_e) = self.up.send_with_u8_array(&binary_data) {
-                
-            }
-        }
+        self.link.borrow_mut().submit(d);
     }
     
     fn sync(&mut self) -> Vec<ClientboundUpdate> {
@@ -139,5 +258,23 @@ impl Upstream for RemoteDatabase {
     fn take_init(&mut self) -> Option<(CachedStats, CachedStats, Vec<Expense>)> {
         self.init_data.take()
     }
+    
+    fn take_pending(&mut self) -> Vec<(Uuid, ClientData, OffsetDateTime)> {
+        std::mem::take(&mut self.pending)
+    }
+    
+    fn export(&mut self, format: ExportFormat, include_revoked: bool)
+            -> tokio::sync::oneshot::Receiver<Result<String, String>> {
+        // Browser sends the session cookie and saves the file on its own.
+        let url = format!("{}/api/export?format={}&revoked={include_revoked}", self.api_base, format.query_name());
+        let opened = web_sys::window()
+            .and_then(|window| window.open_with_url_and_target(&url, "_blank").ok().flatten());
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let _ = tx.send(match opened {
+            Some(_) => Ok("загрузки браузера".to_owned()),
+            None    => Err("браузер не дал открыть выгрузку".to_owned()),
+        });
+        rx
+    }
 }
 
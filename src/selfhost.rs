@@ -1,10 +1,18 @@
 // #[sides(client#selfhost)]
 
 use std::collections::BTreeMap;
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension, Row};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
 use time::OffsetDateTime;
 use uuid::Uuid;
 
+use crate::categories::{budgets_of, categories_of, replace_budgets, replace_categories};
+use crate::migrations::{migrate, SELFHOST_MIGRATIONS};
+use crate::stats::group_totals;
+use crate::tags::{tag_total, tag_totals, tags_column, tags_from_column};
+use crate::import::{ImportReport, StatementRow};
+use crate::export::Exporter;
 use crate::crosstyping::*;
 
 
@@ -13,19 +21,39 @@ pub struct SingleUserSqlite {
     report_stored_expenses: Vec<ClientboundUpdate>,
 }
 impl SingleUserSqlite {
-    fn submit_expense(&mut self, d: ClientData, temp_alias: Uuid)  {
+    /// Stores an expense made at `time` (now if not given), unless one with the same `temp_alias`
+    /// is stored already. Returns whether it was new.
+    fn submit_expense(&mut self, d: ClientData, temp_alias: Uuid, time: Option<OffsetDateTime>) -> bool {
+        let stored = self.conn.query_row("
+SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base,
+       tags
+    FROM spending_records
+    WHERE ifnull(principal, '') = '' AND temp_alias = ?1;
+        ", (temp_alias,), expense_from_row).optional().unwrap();
+        if let Some(expense) = stored {
+            self.report_stored_expenses.push(ClientboundUpdate::NewSpending{expense, temp_alias});
+            return false;
+        }
+        
+        let base_amount = to_base(d.amount, &d.currency, self.exchange_rate(&d.currency));
         let expense = self.conn.query_row("
-INSERT INTO spending_records(amount_indivisible, spend_group) VALUES(?1, ?2)
+INSERT INTO spending_records(amount_indivisible, spend_group, note, currency, amount_base, temp_alias,
+                             unix_date, tags)
+   VALUES(?1, ?2, ?3, ?4, ?5, ?6, ifnull(datetime(?7), datetime('now')), ?8)
    RETURNING id,
              principal,
-             unix_date;
-        ", (d.amount, d.group.clone()), |row| {
+             unix_date,
+             amount_base;
+        ", (d.amount, d.group.clone(), d.note.clone(), d.currency.clone(), base_amount, temp_alias, time,
+            tags_column(&d.tags)),
+        |row| {
             // dbg!(row);
             
             let server = Metadata {
-                uid:       row.get(0)?,
-                principal: row.get(1)?,
-                time:      row.get(2)?,
+                uid:         row.get(0)?,
+                principal:   row.get(1)?,
+                time:        row.get(2)?,
+                base_amount: row.get(3)?,
             };
             Ok(Expense{server, client: d})
         }).unwrap();
@@ -33,34 +61,305 @@ INSERT INTO spending_records(amount_indivisible, spend_group) VALUES(?1, ?2)
         self.report_stored_expenses.push(ClientboundUpdate::NewSpending{
             expense, temp_alias
         });
+        true
     }
     
     fn submit_revoke(&mut self, total_id: Uuid) {
-        let _ = total_id;
-        todo!()
+        let expense = self.conn.query_row("
+UPDATE spending_records SET revoked = TRUE WHERE id = ?1 AND revoked = FALSE
+   RETURNING id,
+             principal,
+             unix_date,
+             amount_indivisible,
+             spend_group,
+             revoked,
+             note,
+             currency,
+             amount_base,
+             tags;
+        ", (total_id,), expense_from_row).optional().unwrap();
+        
+        self.report_stored_expenses.push(match expense {
+            Some(expense) => ClientboundUpdate::Revoked{expense},
+            None          => ClientboundUpdate::RevokeRejected{expense_id: total_id},
+        });
+    }
+    
+    fn submit_restore(&mut self, total_id: Uuid) {
+        let expense = self.conn.query_row("
+UPDATE spending_records SET revoked = FALSE WHERE id = ?1 AND revoked = TRUE
+   RETURNING id,
+             principal,
+             unix_date,
+             amount_indivisible,
+             spend_group,
+             revoked,
+             note,
+             currency,
+             amount_base,
+             tags;
+        ", (total_id,), expense_from_row).optional().unwrap();
+        
+        self.report_stored_expenses.push(match expense {
+            Some(expense) => ClientboundUpdate::Restored{expense},
+            None          => ClientboundUpdate::Rejected{
+                update: ServerboundUpdate::Restore{expense_id: total_id},
+                reason: "expense is missing or not revoked".to_owned(),
+            },
+        });
+    }
+    
+    fn submit_amend(&mut self, total_id: Uuid, d: ClientData, time: OffsetDateTime) {
+        let previous = self.conn.query_row("
+SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base,
+       tags
+    FROM spending_records
+    WHERE id = ?1 AND revoked = FALSE;
+        ", (total_id,), expense_from_row).optional().unwrap();
+        let Some(previous) = previous else {
+            let update = ServerboundUpdate::Amend{expense_id: total_id, info: d, time};
+            let reason = "expense is missing or revoked".to_owned();
+            self.report_stored_expenses.push(ClientboundUpdate::Rejected{update, reason});
+            return;
+        };
+        
+        let base_amount = to_base(d.amount, &d.currency, self.exchange_rate(&d.currency));
+        let tx = self.conn.transaction().unwrap();
+        tx.execute("
+INSERT INTO spending_amendments(expense_id, unix_date, amount_indivisible, spend_group, note,
+                                currency, amount_base, tags)
+    SELECT id, unix_date, amount_indivisible, spend_group, note, currency, amount_base, tags
+    FROM spending_records WHERE id = ?1;
+        ", (total_id,)).unwrap();
+        let expense = tx.query_row("
+UPDATE spending_records SET amount_indivisible = ?2, spend_group = ?3, unix_date = datetime(?4),
+                            note = ?5, currency = ?6, amount_base = ?7, tags = ?8
+    WHERE id = ?1
+    RETURNING id,
+              principal,
+              unix_date,
+              amount_indivisible,
+              spend_group,
+              revoked,
+              note,
+              currency,
+              amount_base,
+              tags;
+        ", (total_id, d.amount, d.group, time, d.note, d.currency, base_amount, tags_column(&d.tags)),
+        expense_from_row).unwrap();
+        tx.commit().unwrap();
+        
+        self.report_stored_expenses.push(ClientboundUpdate::Amended{previous, expense});
+    }
+    
+    /// Rate of `currency` in base currency units per unit.
+    fn exchange_rate(&self, currency: &str) -> f64 {
+        if currency == BASE_CURRENCY {
+            return 1.0;
+        }
+        self.conn.query_row("SELECT base_per_unit FROM exchange_rates WHERE currency = ?1",
+                            (currency,), |row| row.get(0))
+            .expect("no exchange rate for currency of expense")
+    }
+    
+    fn set_exchange_rate(&mut self, currency: &str, base_per_unit: f64) {
+        assert!(is_currency_code(currency) && currency != BASE_CURRENCY);
+        let tx = self.conn.transaction().unwrap();
+        tx.execute("DELETE FROM exchange_rates WHERE currency = ?1", (currency,)).unwrap();
+        tx.execute("INSERT INTO exchange_rates(currency, base_per_unit) VALUES(?1, ?2)",
+                   (currency, base_per_unit)).unwrap();
+        tx.commit().unwrap();
+        
+        self.report_exchange_rates();
+    }
+    
+    fn report_exchange_rates(&mut self) {
+        let rates = self.conn.prepare("SELECT currency, base_per_unit FROM exchange_rates
+                                       ORDER BY currency").unwrap()
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .filter_map(|r| r.ok()).collect::<Vec<_>>();
+        self.report_stored_expenses.push(ClientboundUpdate::ExchangeRates{rates});
+    }
+    
+    fn set_categories(&mut self, categories: &[Category]) {
+        assert!(categories_valid(categories));
+        let tx = self.conn.transaction().unwrap();
+        replace_categories(&tx, None, categories).unwrap();
+        tx.commit().unwrap();
+        
+        self.report_categories();
+    }
+    
+    fn report_categories(&mut self) {
+        let categories = categories_of(&self.conn, None).unwrap();
+        self.report_stored_expenses.push(ClientboundUpdate::Categories{categories});
+    }
+    
+    fn set_budgets(&mut self, budgets: &[Budget]) {
+        assert!(budgets_valid(budgets));
+        let tx = self.conn.transaction().unwrap();
+        replace_budgets(&tx, None, budgets).unwrap();
+        tx.commit().unwrap();
+        
+        self.report_budgets();
+    }
+    
+    fn report_budgets(&mut self) {
+        let budgets = budgets_of(&self.conn, None).unwrap();
+        self.report_stored_expenses.push(ClientboundUpdate::Budgets{budgets});
+    }
+    
+    fn query_history(&mut self, before: (OffsetDateTime, Uuid), amount: usize) {
+        let mut expenses = self.conn.prepare("
+SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base,
+       tags
+    FROM spending_records
+    WHERE revoked = FALSE AND (unix_date, id) < (datetime(?1), ?2)
+    ORDER BY unix_date DESC, id DESC
+    LIMIT ?3;
+        ").unwrap().query_map((before.0, before.1, amount), expense_from_row).unwrap()
+          .filter_map(|r| r.ok()).collect::<Vec<_>>();
+        expenses.reverse();
+        
+        self.report_stored_expenses.push(ClientboundUpdate::RevealHistory{expenses});
+    }
+    
+    fn query_tagged(&mut self, tag: String, before: OffsetDateTime, amount: usize) {
+        let mut expenses = self.conn.prepare("
+SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base,
+       tags
+    FROM spending_records
+    WHERE revoked = FALSE AND unix_date <= datetime(?1)
+      AND id IN (SELECT expense_id FROM expense_tags WHERE tag = ?2)
+    ORDER BY unix_date DESC
+    LIMIT ?3;
+        ").unwrap().query_map((before, &tag, amount), expense_from_row).unwrap()
+            .filter_map(|r| r.ok()).collect::<Vec<_>>();
+        expenses.reverse();
+        
+        let total = tag_total(&self.conn, None, &tag).unwrap();
+        self.report_stored_expenses.push(ClientboundUpdate::RevealTagged{tag, expenses, total});
+    }
+    
+    /// Renders all expenses, oldest first.
+    pub fn export_with(&self, mut exporter: Exporter, include_revoked: bool) -> Result<String> {
+        let expenses = self.conn.prepare("
+SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base,
+       tags
+    FROM spending_records
+    WHERE ?1 OR revoked = FALSE
+    ORDER BY unix_date, id;
+        ")?.query_map((include_revoked,), expense_from_row)?.collect::<Result<Vec<_>, _>>()?;
+        
+        Ok(exporter.begin() + &exporter.page(&expenses) + &exporter.end())
+    }
+    
+    /// Writes all expenses, oldest first, to `path`.
+    fn export_to(&self, path: &Path, format: ExportFormat, include_revoked: bool) -> Result<()> {
+        let text = self.export_with(Exporter::new(format), include_revoked)?;
+        std::fs::write(path, text).with_context(|| format!("cannot write {}", path.display()))
     }
 }
 
-impl Default for SingleUserSqlite {
-    fn default() -> Self {
-        let conn = Connection::open_in_memory().unwrap();
+/// Maps an `id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency,
+/// amount_base, tags` row.
+fn expense_from_row(row: &Row<'_>) -> rusqlite::Result<Expense> {
+    let server = Metadata {
+        uid:         row.get(0)?,
+        principal:   row.get(1)?,
+        time:        row.get(2)?,
+        base_amount: row.get(8)?,
+    };
+    let client = ClientData {
+        amount:    row.get(3)?,
+        group:     row.get(4)?,
+        revoked:   row.get(5)?,
+        note:      row.get(6)?,
+        currency:  row.get(7)?,
+        tags:      tags_from_column(&row.get::<_, String>(9)?),
+    };
+    Ok(Expense{server, client})
+}
+
+/// Database file in the app's storage directory.
+pub fn default_path() -> Option<PathBuf> {
+    eframe::storage_dir(crate::graphics::APP_ID).map(|dir| dir.join("expenses.sqlite3"))
+}
+
+/// Opens (creating if needed) a database file, bringing its schema up to date. The file may be
+/// shared by the app and a `sync` command running at once.
+pub fn open_connection(path: &Path) -> Result<Connection> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut conn = Connection::open(path)
+        .with_context(|| format!("cannot open database at {}", path.display()))?;
+    conn.busy_timeout(std::time::Duration::from_secs(10))?;
+    migrate(&mut conn, SELFHOST_MIGRATIONS)?;
+    Ok(conn)
+}
+
+impl SingleUserSqlite {
+    fn with_connection(conn: Connection) -> Self {
+        let mut this = Self {conn, report_stored_expenses: Vec::with_capacity(1)};
+        this.report_exchange_rates();
+        this.report_categories();
+        this.report_budgets();
+        this
+    }
+    
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self::with_connection(open_connection(path)?))
+    }
+    
+    /// Stores spendings found in a statement, skipping those already imported.
+    pub fn import_rows(&mut self, rows: Vec<StatementRow>, report: &mut ImportReport) {
+        for row in rows {
+            let currency = &row.data.currency;
+            let known_rate = currency == BASE_CURRENCY || self.conn.query_row(
+                "SELECT 1 FROM exchange_rates WHERE currency = ?1", (currency,), |_| Ok(())).is_ok();
+            if !known_rate {
+                report.skipped.push((row.line, format!("no exchange rate for {currency}")));
+                continue;
+            }
+            match self.submit_expense(row.data, row.temp_alias, Some(row.time)) {
+                true  => report.imported += 1,
+                false => report.duplicates += 1,
+            }
+        }
+        self.report_stored_expenses.clear();
+        report.skipped.sort();
+    }
+    
+    fn load_init(&self) -> (CachedStats, CachedStats, Vec<Expense>) {
+        let lifetime_stats = group_totals(&self.conn, None, None, None).unwrap();
         
-        conn.execute_batch("
-BEGIN TRANSACTION;
-CREATE TABLE spending_records (
-    id        BLOB PRIMARY KEY  DEFAULT(randomblob(16)),
-    principal TEXT              DEFAULT NULL,
-    unix_date TEXT              DEFAULT(datetime('now')),
-    amount_indivisible INT8,
-    spend_group        TEXT,
-    revoked            BOOL     DEFAULT FALSE
-);
-CREATE INDEX live_records ON spending_records(principal, revoked, unix_date);
-CREATE INDEX aggregate_records ON spending_records(principal, revoked, spend_group, unix_date);
-COMMIT;
-        ").unwrap();
+        // Enough to cover the calendar month in any timezone; the view drops what is older.
+        let recent_expenses: Vec<Expense> = self.conn.prepare("
+SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base,
+       tags
+    FROM spending_records
+    WHERE revoked = FALSE AND unix_date >= date('now', '-1 day', 'start of month', '-1 day')
+    ORDER BY unix_date ASC;
+        ").unwrap().query_map((), expense_from_row).unwrap().filter_map(|r| r.ok()).collect();
         
-        Self {conn, report_stored_expenses: Vec::with_capacity(1)}
+        let mut month_stats = CachedStats::default();
+        recent_expenses.iter().for_each(|e| month_stats.add(e));
+        (CachedStats::new(lifetime_stats), month_stats, recent_expenses)
+    }
+    
+    fn query_period(&mut self, from: Option<OffsetDateTime>, to: Option<OffsetDateTime>) {
+        let stats = group_totals(&self.conn, None, from, to).unwrap();
+        let tags = tag_totals(&self.conn, None, from, to).unwrap();
+        self.report_stored_expenses.push(ClientboundUpdate::RevealPeriod{from, to, stats, tags});
+    }
+}
+
+impl Default for SingleUserSqlite {
+    fn default() -> Self {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn, SELFHOST_MIGRATIONS).unwrap();
+        Self::with_connection(conn)
     }
 }
 
@@ -70,10 +369,33 @@ impl Upstream for SingleUserSqlite {
             ServerboundUpdate::Revoked{expense_id} => {
                 self.submit_revoke(expense_id)
             },
-            ServerboundUpdate::MadeExpense{info, temp_alias} => {
-                self.submit_expense(info, temp_alias);
+            ServerboundUpdate::MadeExpense{info, temp_alias, time} => {
+                self.submit_expense(info, temp_alias, Some(time));
+            },
+            ServerboundUpdate::Amend{expense_id, info, time} => {
+                self.submit_amend(expense_id, info, time);
+            },
+            ServerboundUpdate::QueryHistory{before, amount} => {
+                self.query_history(before, amount);
+            },
+            ServerboundUpdate::SetExchangeRate{currency, base_per_unit} => {
+                self.set_exchange_rate(&currency, base_per_unit);
+            },
+            ServerboundUpdate::SetCategories{categories} => {
+                self.set_categories(&categories);
+            },
+            ServerboundUpdate::QueryTagged{tag, before, amount} => {
+                self.query_tagged(tag, before, amount);
+            },
+            ServerboundUpdate::SetBudgets{budgets} => {
+                self.set_budgets(&budgets);
+            },
+            ServerboundUpdate::QueryPeriod{from, to} => {
+                self.query_period(from, to);
+            },
+            ServerboundUpdate::Restore{expense_id} => {
+                self.submit_restore(expense_id);
             },
-            ServerboundUpdate::QueryHistory{..} => {},
         }
     }
     
@@ -82,7 +404,17 @@ impl Upstream for SingleUserSqlite {
     }
     
     fn take_init(&mut self) -> Option<(CachedStats, CachedStats, Vec<Expense>)> {
-        Some(Default::default())
+        Some(self.load_init())
+    }
+    
+    fn export(&mut self, format: ExportFormat, include_revoked: bool)
+            -> tokio::sync::oneshot::Receiver<Result<String, String>> {
+        let path = crate::graphics::export_path(format);
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let _ = tx.send(self.export_to(&path, format, include_revoked)
+            .map(|()| path.display().to_string())
+            .map_err(|e| format!("{e:#}")));
+        rx
     }
 }
 
@@ -91,8 +423,10 @@ impl Upstream for SingleUserSqlite {
 // Test version which does not require building foreign code.
 
 pub struct PseudoUpstream {
-    uncommitted_expenses: Vec<(ClientData, Uuid)>,
+    uncommitted_expenses: Vec<(ClientData, Uuid, OffsetDateTime)>,
     uncommitted_revokes: Vec<Uuid>,
+    uncommitted_amends: Vec<(Uuid, ClientData, OffsetDateTime)>,
+    uncommitted_restores: Vec<Uuid>,
     buffer_expenses: BTreeMap<Uuid, Expense>,
 }
 impl Default for PseudoUpstream {
@@ -100,6 +434,8 @@ impl Default for PseudoUpstream {
         Self {
             uncommitted_expenses: Vec::with_capacity(1),
             uncommitted_revokes: vec![],
+            uncommitted_amends: vec![],
+            uncommitted_restores: vec![],
             buffer_expenses: BTreeMap::new(),
         }
     }
@@ -110,30 +446,66 @@ impl Upstream for PseudoUpstream {
             ServerboundUpdate::Revoked{expense_id} => {
                 self.uncommitted_revokes.push(expense_id);
             },
-            ServerboundUpdate::MadeExpense{info, temp_alias} => {
-                self.uncommitted_expenses.push((info, temp_alias));
+            ServerboundUpdate::MadeExpense{info, temp_alias, time} => {
+                self.uncommitted_expenses.push((info, temp_alias, time));
+            },
+            ServerboundUpdate::Amend{expense_id, info, time} => {
+                self.uncommitted_amends.push((expense_id, info, time));
             },
             ServerboundUpdate::QueryHistory{..} => {},
+            ServerboundUpdate::SetExchangeRate{..} => {},
+            ServerboundUpdate::SetCategories{..} => {},
+            ServerboundUpdate::QueryTagged{..} => {},
+            ServerboundUpdate::SetBudgets{..} => {},
+            ServerboundUpdate::QueryPeriod{..} => {},
+            ServerboundUpdate::Restore{expense_id} => {
+                self.uncommitted_restores.push(expense_id);
+            },
         }
     }
     fn sync(&mut self) -> Vec<ClientboundUpdate> {
         let mut v = Vec::with_capacity(self.uncommitted_expenses.len() +
                                        self.uncommitted_revokes.len());
-        for (client, temp_alias) in self.uncommitted_expenses.drain(..) {
+        for (client, temp_alias, time) in self.uncommitted_expenses.drain(..) {
             let server = Metadata {
                 uid: Uuid::new_v4(),
-                time: OffsetDateTime::now_local().unwrap(),
-                principal: None
+                time,
+                principal: None,
+                base_amount: client.amount,
             };
             let uid = server.uid.clone();
             let expense = Expense{server, client};
             self.buffer_expenses.insert(uid, expense.clone());
             v.push(ClientboundUpdate::NewSpending{expense, temp_alias});
         }
+        for (expense_id, client, time) in self.uncommitted_amends.drain(..) {
+            let Some(expense) = self.buffer_expenses.get_mut(&expense_id).filter(|e| !e.client.revoked) else {
+                let update = ServerboundUpdate::Amend{expense_id, info: client, time};
+                v.push(ClientboundUpdate::Rejected{update, reason: "expense is missing or revoked".to_owned()});
+                continue
+            };
+            let previous = expense.clone();
+            expense.client = client;
+            expense.server.time = time;
+            v.push(ClientboundUpdate::Amended{previous, expense: expense.clone()});
+        }
         v.extend(self.uncommitted_revokes.drain(..)
-                     .flat_map(|i| -> Option<_> {
-                         Some(ClientboundUpdate::Revoked{expense: self.buffer_expenses.remove(&i)?})
+                     .map(|i| match self.buffer_expenses.get_mut(&i).filter(|e| !e.client.revoked) {
+                         Some(expense) => {
+                             expense.client.revoked = true;
+                             ClientboundUpdate::Revoked{expense: expense.clone()}
+                         },
+                         None          => ClientboundUpdate::RevokeRejected{expense_id: i},
                      }));
+        for expense_id in self.uncommitted_restores.drain(..) {
+            let Some(expense) = self.buffer_expenses.get_mut(&expense_id).filter(|e| e.client.revoked) else {
+                let update = ServerboundUpdate::Restore{expense_id};
+                v.push(ClientboundUpdate::Rejected{update, reason: "expense is missing or not revoked".to_owned()});
+                continue
+            };
+            expense.client.revoked = false;
+            v.push(ClientboundUpdate::Restored{expense: expense.clone()});
+        }
         v
     }
     fn take_init(&mut self) -> Option<(CachedStats, CachedStats, Vec<Expense>)> {
@@ -1,25 +1,37 @@
-use axum::extract::{State, Extension, Path, Request, ws::{CloseFrame, Message, close_code}};
+use axum::extract::{State, Extension, Json, Path, Query, Request, ws::{CloseFrame, Message, close_code}};
 use axum_extra::extract::{cookie::{Key, Cookie, SameSite}, SignedCookieJar};
 use axum::{extract::WebSocketUpgrade, response::IntoResponse};
-use axum::{routing::{get, post}, Router, RequestExt};
+use axum::{routing::{delete, get, post}, Router, RequestExt};
 use axum::middleware::map_request_with_state;
 use tokio::sync::broadcast::error::RecvError;
 use postcard::{to_stdvec, from_bytes};
 use tokio::sync::oneshot::Sender;
 use tokio::net::TcpListener;
-use axum::http::HeaderMap;
+use axum::http::{HeaderMap, StatusCode};
+use serde::Deserialize;
 use std::sync::Arc;
+use time::UtcOffset;
 use futures::*;
 
 
-use crate::crosstyping::ServerboundUpdate;
-use sqlite::MultiuserDb;
+use crate::crosstyping::{ClientboundUpdate, ExportFormat, ServerboundUpdate, SyncRequest, BASE_CURRENCY};
+use crate::export::{parse_utc_offset, Exporter, DEFAULT_FUNDING_ACCOUNT};
+use crate::import::{self, CategoryRule, CsvMapping, ImportReport, StatementRow};
+use sqlite::{MultiuserDb, Submitted};
+pub use config::ServerConfig;
 mod sqlite;
+mod config;
+mod rest;
 
 
 #[derive(Clone)]
 pub struct UserAuth(String);
 
+/// Most expenses a single `QueryHistory` may reveal.
+const HISTORY_PAGE_LIMIT: usize = 1000;
+/// Expenses read from database at once while exporting.
+const EXPORT_PAGE: usize = 500;
+
 
 fn logon_cookie(principal: String) -> Cookie<'static> {
     let mut login_cookie = Cookie::new("user", principal);
@@ -36,8 +48,9 @@ pub async fn login(
     Path(device): Path<String>,
     totp: String
 ) -> impl IntoResponse {
-    let principal = db.login_impl(&device, &totp).await.map_err(|e| e.to_string())?;
-    Ok::<_, String>(jar.add(logon_cookie(principal)))
+    let principal = db.login_impl(&device, &totp).await
+        .map_err(|e| (StatusCode::UNAUTHORIZED, format!("{e:#}")))?;
+    Ok::<_, (StatusCode, String)>(jar.add(logon_cookie(principal)))
 }
 pub async fn register(
     State(db): State<Arc<MultiuserDb>>,
@@ -46,19 +59,175 @@ pub async fn register(
     Path(device): Path<String>,
     principal_reg: Option<String>
 ) -> impl IntoResponse {
+    let rejected = |reason: String| (StatusCode::BAD_REQUEST, reason);
     let (totp, principal) = match (maybe_auth, principal_reg) {
         (Some(Extension(UserAuth(principal))), None) => {
-            (db.register_from(&principal, &device).await.map_err(|e| e.to_string())?, principal)
+            (db.register_from(&principal, &device).await.map_err(|e| rejected(format!("{e:#}")))?, principal)
         },
         (None, Some(principal)) => {
-            if principal.len() <= 1 {return Err("invalid principal name".to_owned());}
-            (db.register_impl(&device, &principal).await.map_err(|e| e.to_string())?, principal)
+            if principal.len() <= 1 {return Err(rejected("invalid principal name".to_owned()));}
+            (db.register_impl(&device, &principal).await.map_err(|e| rejected(format!("{e:#}")))?, principal)
         },
-        _ => return Err("cannot register in name of other principal when logged in".to_owned()),
+        _ => return Err(rejected("cannot register in name of other principal when logged in".to_owned())),
     };
     println!("{device} -> {principal}");
     Ok((jar.add(logon_cookie(principal)), totp))
 }
+/// Exchanges changed records with a self-hosted database; bodies are postcard-encoded
+/// `SyncRequest` and `SyncReply`.
+pub async fn handle_sync(
+    State(db): State<Arc<MultiuserDb>>,
+    maybe_auth: Option<Extension<UserAuth>>,
+    body: axum::body::Bytes,
+) -> impl IntoResponse {
+    let Some(Extension(UserAuth(principal))) = maybe_auth else {
+        return Err((StatusCode::UNAUTHORIZED, "not logged in".to_owned()));
+    };
+    let request: SyncRequest = from_bytes(&body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("malformed sync request: {e}")))?;
+    let reply = db.sync(&principal, request).await
+        .map_err(|e| (StatusCode::CONFLICT, format!("{e:#}")))?;
+    to_stdvec(&reply).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}")))
+}
+#[derive(Deserialize)]
+pub struct CsvImport {
+    mapping: CsvMapping,
+    #[serde(default)]
+    rules: Vec<CategoryRule>,
+    csv: String,
+}
+
+/// Stores spendings found in a statement like ones submitted by clients, skipping those already
+/// imported.
+async fn store_rows(db: &MultiuserDb, principal: &str, rows: Vec<StatementRow>, report: &mut ImportReport) {
+    for row in rows {
+        match db.submit_expense(principal, row.data, row.temp_alias, Some(row.time)).await {
+            Ok(Submitted::Stored(_))    => report.imported += 1,
+            Ok(Submitted::Duplicate(_)) => report.duplicates += 1,
+            Err(e) => report.skipped.push((row.line, format!("{e:#}"))),
+        }
+    }
+    report.skipped.sort();
+}
+
+/// Imports a bank statement sent as JSON `{"mapping": {...}, "rules": [...], "csv": "..."}`,
+/// replying with JSON `ImportReport`.
+pub async fn handle_import_csv(
+    State(db): State<Arc<MultiuserDb>>,
+    maybe_auth: Option<Extension<UserAuth>>,
+    Json(request): Json<CsvImport>,
+) -> impl IntoResponse {
+    let Some(Extension(UserAuth(principal))) = maybe_auth else {
+        return Err((StatusCode::UNAUTHORIZED, "not logged in".to_owned()));
+    };
+    let mut report = ImportReport::default();
+    let rows = import::parse_csv(&request.csv, &request.mapping, &request.rules, &mut report.skipped)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}")))?;
+    store_rows(&db, &principal, rows, &mut report).await;
+    Ok(Json(report))
+}
+
+#[derive(Deserialize)]
+pub struct StatementImport {
+    #[serde(default)]
+    rules: Vec<CategoryRule>,
+    /// Currency of all amounts in a QIF file, base one by default; OFX files name their own.
+    currency: Option<String>,
+    text: String,
+}
+
+/// Imports an OFX (or QFX) statement sent as JSON `{"rules": [...], "text": "..."}`, replying with
+/// JSON `ImportReport`. Transactions are recognized by FITID when imported again.
+pub async fn handle_import_ofx(
+    State(db): State<Arc<MultiuserDb>>,
+    maybe_auth: Option<Extension<UserAuth>>,
+    Json(request): Json<StatementImport>,
+) -> impl IntoResponse {
+    let Some(Extension(UserAuth(principal))) = maybe_auth else {
+        return Err((StatusCode::UNAUTHORIZED, "not logged in".to_owned()));
+    };
+    let mut report = ImportReport::default();
+    let rows = import::parse_ofx(&request.text, &request.rules, &mut report.skipped)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}")))?;
+    store_rows(&db, &principal, rows, &mut report).await;
+    Ok(Json(report))
+}
+
+/// Imports a QIF file sent as JSON `{"rules": [...], "currency": "...", "text": "..."}`, replying
+/// with JSON `ImportReport`.
+pub async fn handle_import_qif(
+    State(db): State<Arc<MultiuserDb>>,
+    maybe_auth: Option<Extension<UserAuth>>,
+    Json(request): Json<StatementImport>,
+) -> impl IntoResponse {
+    let Some(Extension(UserAuth(principal))) = maybe_auth else {
+        return Err((StatusCode::UNAUTHORIZED, "not logged in".to_owned()));
+    };
+    let currency = request.currency.as_deref().unwrap_or(BASE_CURRENCY);
+    let mut report = ImportReport::default();
+    let rows = import::parse_qif(&request.text, currency, &request.rules, &mut report.skipped)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}")))?;
+    store_rows(&db, &principal, rows, &mut report).await;
+    Ok(Json(report))
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    format: Option<ExportFormat>,
+    #[serde(default)]
+    revoked: bool,
+    /// Ledger account expenses are paid from.
+    funding: Option<String>,
+    /// UTC offset like `+03:00` for dates of ledger entries; UTC by default.
+    tz: Option<String>,
+}
+
+/// Streams all expenses of the principal as CSV (default), JSON or ledger journal, oldest first;
+/// revoked ones are included with `?revoked=true`.
+pub async fn handle_export(
+    State(db): State<Arc<MultiuserDb>>,
+    maybe_auth: Option<Extension<UserAuth>>,
+    Query(query): Query<ExportQuery>,
+) -> impl IntoResponse {
+    let Some(Extension(UserAuth(principal))) = maybe_auth else {
+        return Err((StatusCode::UNAUTHORIZED, "not logged in".to_owned()));
+    };
+    let format = query.format.unwrap_or(ExportFormat::Csv);
+    let offset = match query.tz.as_deref().map(parse_utc_offset) {
+        Some(None) => return Err((StatusCode::BAD_REQUEST, "tz must be UTC offset like +03:00".to_owned())),
+        Some(Some(offset)) => offset,
+        None => UtcOffset::UTC,
+    };
+    let funding = query.funding.as_deref().unwrap_or(DEFAULT_FUNDING_ACCOUNT);
+    let exporter = Exporter::new(format).with_ledger(funding, offset);
+    let head = exporter.begin();
+    
+    // State is the exporter, and the last exported expense; `None` once everything is sent.
+    let pages = stream::unfold(Some((exporter, None)), move |state| {
+        let (db, principal) = (db.clone(), principal.clone());
+        async move {
+            let (mut exporter, after) = state?;
+            match db.export_page(&principal, query.revoked, after, EXPORT_PAGE).await {
+                Ok(page) if page.is_empty() => Some((Ok(exporter.end()), None)),
+                Ok(page) => {
+                    let last = page.last().map(|e| (e.server.time, e.server.uid));
+                    Some((Ok(exporter.page(&page)), Some((exporter, last))))
+                },
+                Err(e) => Some((Err(std::io::Error::other(format!("{e:#}"))), None)),
+            }
+        }
+    });
+    let body = axum::body::Body::from_stream(stream::once(async {Ok(head)}).chain(pages));
+    
+    let content_type = match format {
+        ExportFormat::Csv    => "text/csv; charset=utf-8",
+        ExportFormat::Json   => "application/json",
+        ExportFormat::Ledger => "text/plain; charset=utf-8",
+    };
+    let disposition = format!("attachment; filename=\"expenses.{}\"", format.extension());
+    Ok(([("Content-Type", content_type.to_owned()), ("Content-Disposition", disposition)], body))
+}
+
 pub async fn handle_me(
     maybe_principal: Option<Extension<UserAuth>>,
 ) -> String {
@@ -128,20 +297,71 @@ pub async fn handle_websocket(
                     Message::Binary(b)          => b,
                     _ => break close_code::UNSUPPORTED,
                 };
-                let Ok(serverbound_req) = from_bytes(&bytes_msg) else {
+                let Ok(serverbound_req): Result<ServerboundUpdate, _> = from_bytes(&bytes_msg) else {
                     break close_code::INVALID
                 };
                 
-                if let Err(e) = match serverbound_req {
-                    ServerboundUpdate::MadeExpense{info, temp_alias} =>
-                      db.submit_expense(&principal, info, temp_alias).await.map(|_| ()),
+                // Replies meant for this client only, bypassing the broadcast channel.
+                let rejectable = serverbound_req.clone();
+                let direct_reply = match serverbound_req {
+                    ServerboundUpdate::MadeExpense{info, temp_alias, time} =>
+                      db.submit_expense(&principal, info, temp_alias, Some(time)).await.map(|s| match s {
+                          Submitted::Stored(_)         => None,
+                          Submitted::Duplicate(expense) => Some(ClientboundUpdate::NewSpending{expense, temp_alias}),
+                      }),
                     ServerboundUpdate::Revoked{expense_id} =>
-                      db.submit_revoke(&principal, expense_id).await.map(|_| ()),
-                    ServerboundUpdate::QueryHistory { .. } => Ok(()),
-                } {
-                    eprintln!("Database operation failed: {e:?}");
-                    break close_code::ERROR
+                      db.submit_revoke(&principal, expense_id).await.map(|r| match r {
+                          Some(_) => None,
+                          None    => Some(ClientboundUpdate::RevokeRejected{expense_id}),
+                      }),
+                    ServerboundUpdate::Restore{expense_id} =>
+                      db.submit_restore(&principal, expense_id).await.map(|r| r.is_none().then(|| {
+                          let reason = "expense is missing or not revoked".to_owned();
+                          ClientboundUpdate::Rejected{update: rejectable.clone(), reason}
+                      })),
+                    ServerboundUpdate::Amend{expense_id, info, time} =>
+                      db.submit_amend(&principal, expense_id, info, time).await.map(|a| a.is_none().then(|| {
+                          let reason = "expense is missing or revoked".to_owned();
+                          ClientboundUpdate::Rejected{update: rejectable.clone(), reason}
+                      })),
+                    ServerboundUpdate::SetExchangeRate{currency, base_per_unit} =>
+                      db.set_exchange_rate(&principal, &currency, base_per_unit).await.map(|_| None),
+                    ServerboundUpdate::SetCategories{categories} =>
+                      db.set_categories(&principal, categories).await.map(|_| None),
+                    ServerboundUpdate::SetBudgets{budgets} =>
+                      db.set_budgets(&principal, budgets).await.map(|_| None),
+                    ServerboundUpdate::QueryHistory{before, amount} =>
+                      db.query_history(&principal, before, amount.min(HISTORY_PAGE_LIMIT)).await
+                        .map(|expenses| Some(ClientboundUpdate::RevealHistory{expenses})),
+                    ServerboundUpdate::QueryTagged{tag, before, amount} =>
+                      db.query_tagged(&principal, &tag, before, amount.min(HISTORY_PAGE_LIMIT)).await
+                        .map(|(expenses, total)| Some(ClientboundUpdate::RevealTagged{tag, expenses, total})),
+                    ServerboundUpdate::QueryPeriod{from, to} =>
+                      db.period_stats(&principal, from, to).await
+                        .map(|(stats, tags)| Some(ClientboundUpdate::RevealPeriod{from, to, stats, tags})),
+                };
+                // Closing would not help, as the client replays undelivered updates on reconnecting.
+                let direct_reply = match direct_reply {
+                    Ok(r) => r,
+                    Err(e) => {
+                        eprintln!("Database operation failed: {e:?}");
+                        Some(ClientboundUpdate::Rejected{update: rejectable, reason: format!("{e:#}")})
+                    }
                 };
+                if let Some(reply) = direct_reply {
+                    let bytes_msg = match to_stdvec(&reply) {
+                        Ok(b) => b,
+                        Err(e) => {
+                            eprintln!("postcard ser failed: {e:?}");
+                            break close_code::ERROR
+                        }
+                    };
+                    let msg = Message::Binary(bytes_msg);
+                    if let Err(e) = ws_write.send(msg).await {
+                        eprintln!("WS sending failed: {e:?}");
+                        break close_code::ERROR
+                    }
+                }
                 
                 std::mem::drop(ws_read_future);
                 ws_read_future = ws_read.next().boxed();
@@ -162,10 +382,13 @@ pub async fn handle_websocket(
 /// Reflects https://docs.rs/axum/latest/src/axum/handler/mod.rs.html#254-256
 fn check_handler<T>(_: &T) where T: IntoResponse + Clone + Send + Sync + 'static {}
 
-pub async fn serve_forever(bind_ip: &'static str, session_signing_key: Vec<u8>,
-        root_key_out: Option<Sender<(&'static str, Vec<u8>)>>) {
-    let db = Arc::new(MultiuserDb::mem_new());
-    let session_signing_key = Key::from(&session_signing_key);
+pub async fn serve_forever(config: ServerConfig, root_key_out: Option<Sender<(&'static str, Vec<u8>)>>) {
+    let db = match &config.database {
+        Some(path) => MultiuserDb::open(path).expect("failed to open expenses database"),
+        None       => MultiuserDb::mem_new(),
+    };
+    let db = Arc::new(db);
+    let session_signing_key = Key::from(&config.session_key);
     
     if let Some(sender) = root_key_out {
         let root_totp = db.register_impl("root", "root").await.expect("root registration fault");
@@ -187,6 +410,14 @@ pub async fn serve_forever(bind_ip: &'static str, session_signing_key: Vec<u8>,
         .route("/api/register/:device", post(register))
         .route("/api/login/:device", post(login))
         .route("/api/me", get(handle_me))
+        .route("/api/sync", post(handle_sync))
+        .route("/api/import/csv", post(handle_import_csv))
+        .route("/api/import/ofx", post(handle_import_ofx))
+        .route("/api/import/qif", post(handle_import_qif))
+        .route("/api/export", get(handle_export))
+        .route("/api/expenses", get(rest::list_expenses).post(rest::create_expense))
+        .route("/api/expenses/:id", delete(rest::revoke_expense))
+        .route("/api/stats", get(rest::stats))
         .route("/ws", get(handle_websocket))
         .with_state(db)
         .layer(map_request_with_state(session_signing_key,
@@ -205,7 +436,7 @@ pub async fn serve_forever(bind_ip: &'static str, session_signing_key: Vec<u8>,
         .route("/icon-64.png", typed_load!("image/png" @ "../assets/icon-64.png"))
         .route("/", typed_load!("text/html" @ "../assets/index.html"));
 
-    let listener = TcpListener::bind(bind_ip).await.unwrap();
+    let listener = TcpListener::bind(&config.bind).await.unwrap();
     axum::serve(listener, app).await.unwrap();
 }
 
@@ -0,0 +1,170 @@
+// #[sides(server)]
+
+use anyhow::{bail, ensure, Context, Result};
+use std::path::{Path, PathBuf};
+
+
+const DEFAULT_BIND: &str = "0.0.0.0:4341";
+const DEFAULT_CONFIG: &str = "tea-server.conf";
+const DEFAULT_DATABASE: &str = "tea-server.sqlite3";
+const DEFAULT_KEY_FILE: &str = "tea-session.key";
+/// `cookie::Key` refuses anything shorter.
+const SESSION_KEY_LEN: usize = 64;
+
+const USAGE: &str = "\
+Usage: ting-expense-a [--config FILE] [--bind ADDR] [--database FILE] [--session-key-file FILE]
+
+Settings are taken from flags, then TEA_* environment variables, then the config file, then defaults.
+The config file holds `name = value` lines; names are bind, database, session_key_file and
+session_key (hex). When no key is given, one is generated and stored in the session key file.
+
+    --config FILE            TEA_CONFIG            (default tea-server.conf, may be absent)
+    --bind ADDR              TEA_BIND              (default 0.0.0.0:4341)
+    --database FILE          TEA_DATABASE          (default tea-server.sqlite3)
+    --session-key-file FILE  TEA_SESSION_KEY_FILE  (default tea-session.key)
+                             TEA_SESSION_KEY       (hex, overrides the key file)";
+
+
+pub struct ServerConfig {
+    pub bind: String,
+    pub database: Option<PathBuf>,   // None stands for in-memory
+    /// Signs the `user` cookie; whoever knows it can log in as anyone.
+    pub session_key: Vec<u8>,
+}
+
+/// Settings as given by one of sources, before defaults are applied.
+#[derive(Default)]
+struct Layer {
+    config: Option<PathBuf>,
+    bind: Option<String>,
+    database: Option<PathBuf>,
+    session_key_file: Option<PathBuf>,
+    session_key: Option<String>,
+}
+impl Layer {
+    fn or(self, other: Layer) -> Layer {
+        Layer {
+            config:           self.config.or(other.config),
+            bind:             self.bind.or(other.bind),
+            database:         self.database.or(other.database),
+            session_key_file: self.session_key_file.or(other.session_key_file),
+            session_key:      self.session_key.or(other.session_key),
+        }
+    }
+    
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Layer> {
+        let mut layer = Layer::default();
+        while let Some(flag) = args.next() {
+            if flag == "--help" || flag == "-h" {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            let value = args.next().with_context(|| format!("flag {flag} expects a value\n\n{USAGE}"))?;
+            match flag.as_str() {
+                "--config"           => layer.config = Some(value.into()),
+                "--bind"             => layer.bind = Some(value),
+                "--database"         => layer.database = Some(value.into()),
+                "--session-key-file" => layer.session_key_file = Some(value.into()),
+                _ => bail!("unknown flag {flag}\n\n{USAGE}"),
+            }
+        }
+        Ok(layer)
+    }
+    
+    fn from_env() -> Layer {
+        let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+        Layer {
+            config:           var("TEA_CONFIG").map(Into::into),
+            bind:             var("TEA_BIND"),
+            database:         var("TEA_DATABASE").map(Into::into),
+            session_key_file: var("TEA_SESSION_KEY_FILE").map(Into::into),
+            session_key:      var("TEA_SESSION_KEY"),
+        }
+    }
+    
+    fn from_file(path: &Path, required: bool) -> Result<Layer> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => return Ok(Layer::default()),
+            Err(e) => return Err(e).with_context(|| format!("cannot read config {}", path.display())),
+        };
+        
+        let mut layer = Layer::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {continue;}
+            
+            let Some((name, value)) = line.split_once('=') else {
+                bail!("{}:{}: expected `name = value`", path.display(), i + 1);
+            };
+            let value = value.trim().trim_matches('"').to_owned();
+            match name.trim() {
+                "bind"             => layer.bind = Some(value),
+                "database"         => layer.database = Some(value.into()),
+                "session_key_file" => layer.session_key_file = Some(value.into()),
+                "session_key"      => layer.session_key = Some(value),
+                other => bail!("{}:{}: unknown setting {other}", path.display(), i + 1),
+            }
+        }
+        Ok(layer)
+    }
+}
+
+
+impl ServerConfig {
+    /// Gathers settings from command line, environment and config file, generating the session key
+    /// on first start.
+    #[cfg_attr(feature = "graphics", allow(dead_code, reason = "embedded server is ephemeral"))]
+    pub fn load() -> Result<Self> {
+        let given = Layer::from_args(std::env::args().skip(1))?.or(Layer::from_env());
+        let required = given.config.is_some();
+        let config = given.config.clone().unwrap_or_else(|| DEFAULT_CONFIG.into());
+        let layer = given.or(Layer::from_file(&config, required)?);
+        
+        let session_key = match layer.session_key {
+            Some(hex_key) => hex::decode(hex_key.trim()).context("session key is not valid hex")?,
+            None => load_or_generate_key(
+                &layer.session_key_file.unwrap_or_else(|| DEFAULT_KEY_FILE.into()))?,
+        };
+        ensure!(session_key.len() >= SESSION_KEY_LEN,
+                "session key must be at least {SESSION_KEY_LEN} bytes, got {}", session_key.len());
+        
+        Ok(Self {
+            bind: layer.bind.unwrap_or_else(|| DEFAULT_BIND.to_owned()),
+            database: Some(layer.database.unwrap_or_else(|| DEFAULT_DATABASE.into())),
+            session_key,
+        })
+    }
+    
+    /// In-memory server with a key that is forgotten on exit, so are the sessions.
+    #[cfg_attr(not(feature = "graphics"), allow(dead_code, reason = "only embedded server is ephemeral"))]
+    pub fn ephemeral(bind: &str) -> Self {
+        Self {bind: bind.to_owned(), database: None, session_key: random_key()}
+    }
+}
+
+
+fn random_key() -> Vec<u8> {
+    let mut key = vec![0_u8; SESSION_KEY_LEN];
+    getrandom::fill(&mut key).expect("no randomness source for the session key");
+    key
+}
+
+fn load_or_generate_key(path: &Path) -> Result<Vec<u8>> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => return hex::decode(text.trim())
+            .with_context(|| format!("session key in {} is not valid hex", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+    }
+    
+    let key = random_key();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)] std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)
+        .with_context(|| format!("cannot create session key file {}", path.display()))?;
+    std::io::Write::write_all(&mut file, hex::encode(&key).as_bytes())?;
+    println!("Generated new session key in {}.", path.display());
+    Ok(key)
+}
@@ -0,0 +1,243 @@
+// #[sides(server)]
+
+use axum::extract::{State, Extension, Json, Path, Query};
+use axum::{http::StatusCode, response::IntoResponse};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use super::sqlite::{ExpenseFilter, MultiuserDb, Submitted};
+use super::{UserAuth, HISTORY_PAGE_LIMIT};
+use crate::crosstyping::*;
+use crate::export::ExportRow;
+
+
+// JSON counterpart of the WebSocket protocol, for scripts and integrations. Amounts are decimal
+// strings, times are RFC 3339; changes reach connected clients just like those made in the app.
+
+type Rejection = (StatusCode, String);
+
+fn principal_of(maybe_auth: Option<Extension<UserAuth>>) -> Result<String, Rejection> {
+    match maybe_auth {
+        Some(Extension(UserAuth(principal))) => Ok(principal),
+        None => Err((StatusCode::UNAUTHORIZED, "not logged in".to_owned())),
+    }
+}
+
+fn bad_request(e: anyhow::Error) -> Rejection {
+    (StatusCode::BAD_REQUEST, format!("{e:#}"))
+}
+
+/// Page position after the last listed expense, as `<unix time>_<id>`.
+fn cursor_after(e: &Expense) -> String {
+    format!("{}_{}", e.server.time.unix_timestamp(), e.server.uid)
+}
+
+fn parse_cursor(cursor: &str) -> Option<(OffsetDateTime, Uuid)> {
+    let (time, id) = cursor.split_once('_')?;
+    Some((OffsetDateTime::from_unix_timestamp(time.parse().ok()?).ok()?, id.parse().ok()?))
+}
+
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    from: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    to: Option<OffsetDateTime>,
+    /// Empty for unclassified expenses.
+    group: Option<String>,
+    currency: Option<String>,
+    tag: Option<String>,
+    #[serde(default)]
+    revoked: bool,
+    /// `next` of the previous page.
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ExpensePage<'a> {
+    expenses: Vec<ExportRow<'a>>,
+    /// Cursor for the following page, absent on the last one.
+    next: Option<String>,
+}
+
+/// `GET /api/expenses?from=&to=&group=&currency=&tag=&revoked=&cursor=&limit=`: expenses, newest
+/// first, made in `[from, to)`; at most `limit` (100 by default) at once.
+pub async fn list_expenses(
+    State(db): State<Arc<MultiuserDb>>,
+    maybe_auth: Option<Extension<UserAuth>>,
+    Query(query): Query<ListQuery>,
+) -> Result<impl IntoResponse, Rejection> {
+    let principal = principal_of(maybe_auth)?;
+    let before = match query.cursor.as_deref().map(parse_cursor) {
+        Some(None) => return Err((StatusCode::BAD_REQUEST, "malformed cursor".to_owned())),
+        Some(Some(before)) => Some(before),
+        None => None,
+    };
+    let limit = query.limit.unwrap_or(100).clamp(1, HISTORY_PAGE_LIMIT);
+    let filter = ExpenseFilter {
+        from: query.from,
+        to: query.to,
+        group: query.group,
+        currency: query.currency.map(|c| c.to_uppercase()),
+        tag: query.tag,
+        include_revoked: query.revoked,
+    };
+    
+    let expenses = db.list_expenses(&principal, &filter, before, limit).await.map_err(bad_request)?;
+    let next = expenses.last().filter(|_| expenses.len() == limit).map(cursor_after);
+    Ok(Json(ExpensePage {expenses: expenses.iter().map(ExportRow::new).collect(), next}).into_response())
+}
+
+
+#[derive(Deserialize)]
+pub struct NewExpense {
+    /// Decimal, as a string like `"12.50"` or a number.
+    amount: serde_json::Value,
+    currency: Option<String>,
+    group: Option<String>,
+    note: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    /// When the money was spent; now by default.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    time: Option<OffsetDateTime>,
+    /// Posting again with the same alias stores the expense once.
+    temp_alias: Option<Uuid>,
+}
+
+/// `POST /api/expenses`: stores an expense, replying with it; `201 Created` unless it was stored
+/// before under the same `temp_alias`.
+pub async fn create_expense(
+    State(db): State<Arc<MultiuserDb>>,
+    maybe_auth: Option<Extension<UserAuth>>,
+    Json(request): Json<NewExpense>,
+) -> Result<impl IntoResponse, Rejection> {
+    let principal = principal_of(maybe_auth)?;
+    let currency = request.currency.map(|c| c.to_uppercase()).unwrap_or(BASE_CURRENCY.to_owned());
+    if !is_currency_code(&currency) {
+        return Err((StatusCode::BAD_REQUEST, format!("{currency:?} is not a currency code")));
+    }
+    let amount_text = match &request.amount {
+        serde_json::Value::String(text) => text.clone(),
+        serde_json::Value::Number(number) => number.to_string(),
+        _ => return Err((StatusCode::BAD_REQUEST, "amount must be a decimal".to_owned())),
+    };
+    let amount = parse_amount(&amount_text, &currency).filter(|a| *a > 0).ok_or_else(||
+        (StatusCode::BAD_REQUEST, format!("{amount_text} is not a positive amount in {currency}")))?;
+    
+    let data = ClientData {
+        amount,
+        group: request.group.map(|g| normalize_category(&g)).filter(|g| !g.is_empty()),
+        revoked: false,
+        note: request.note.filter(|n| !n.is_empty()),
+        currency,
+        tags: normalize_tags(request.tags.iter().map(String::as_str)),
+    };
+    let temp_alias = request.temp_alias.unwrap_or_else(Uuid::new_v4);
+    let (status, expense) = match db.submit_expense(&principal, data, temp_alias, request.time).await {
+        Ok(Submitted::Stored(expense))    => (StatusCode::CREATED, expense),
+        Ok(Submitted::Duplicate(expense)) => (StatusCode::OK, expense),
+        Err(e) => return Err(bad_request(e)),
+    };
+    Ok((status, Json(ExportRow::new(&expense))).into_response())
+}
+
+
+/// `DELETE /api/expenses/:id`: revokes a live expense, replying with it.
+pub async fn revoke_expense(
+    State(db): State<Arc<MultiuserDb>>,
+    maybe_auth: Option<Extension<UserAuth>>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, Rejection> {
+    let principal = principal_of(maybe_auth)?;
+    match db.submit_revoke(&principal, id).await.map_err(bad_request)? {
+        Some(expense) => Ok(Json(ExportRow::new(&expense)).into_response()),
+        None => Err((StatusCode::NOT_FOUND, format!("no live expense {id}"))),
+    }
+}
+
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    from: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    to: Option<OffsetDateTime>,
+}
+
+#[derive(Serialize)]
+struct GroupTotal {
+    /// Absent for unclassified expenses.
+    group: Option<String>,
+    total: String,
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct CategoryTotal {
+    category: String,
+    /// Absent for top-level categories.
+    parent: Option<String>,
+    total: String,
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct TagTotal {
+    tag: String,
+    total: String,
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    currency: &'static str,
+    total: String,
+    count: usize,
+    groups: Vec<GroupTotal>,
+    categories: Vec<CategoryTotal>,
+    tags: Vec<TagTotal>,
+}
+
+/// `GET /api/stats?from=&to=`: live expenses made in `[from, to)` (all by default) summed in base
+/// currency, overall and by group, largest group first. `categories` roll subcategories up into
+/// every parent, like "транспорт" for "транспорт/такси", parents first. `tags` count expenses
+/// with several tags for each of them.
+pub async fn stats(
+    State(db): State<Arc<MultiuserDb>>,
+    maybe_auth: Option<Extension<UserAuth>>,
+    Query(query): Query<StatsQuery>,
+) -> Result<impl IntoResponse, Rejection> {
+    let principal = principal_of(maybe_auth)?;
+    let totals = db.totals(&principal, query.from, query.to).await.map_err(bad_request)?;
+    let groups = totals.groups.into_iter()
+        .map(|(group, total, count)| GroupTotal {
+            group,
+            total: format_amount(total, BASE_CURRENCY),
+            count,
+        })
+        .collect();
+    let categories = totals.rolled_up.into_iter()
+        .map(|(category, total, count)| CategoryTotal {
+            parent: parent_category(&category).map(|parent| parent.to_owned()),
+            category,
+            total: format_amount(total, BASE_CURRENCY),
+            count,
+        })
+        .collect();
+    let tags = totals.tags.into_iter()
+        .map(|(tag, total, count)| TagTotal {tag, total: format_amount(total, BASE_CURRENCY), count})
+        .collect();
+    Ok(Json(Stats {
+        currency: BASE_CURRENCY,
+        total: format_amount(totals.total, BASE_CURRENCY),
+        count: totals.count,
+        groups,
+        categories,
+        tags,
+    }))
+}
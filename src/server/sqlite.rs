@@ -1,15 +1,52 @@
 // #[sides(server)]
 
-use rusqlite::{OptionalExtension, Connection, TransactionBehavior};
+use rusqlite::{OptionalExtension, Connection, Row, TransactionBehavior};
 use tokio::sync::{broadcast, Mutex, RwLock};
 use anyhow::{ensure, Result, Context};
 use totp_rs::{Algorithm, TOTP};
 use std::collections::HashMap;
+use time::OffsetDateTime;
 use uuid::Uuid;
 
+use crate::categories::{budgets_of, categories_of, replace_budgets, replace_categories};
+use crate::stats::group_totals;
+use crate::tags::{tag_total, tag_totals, tags_column, tags_from_column};
+use crate::migrations::{migrate, SERVER_MIGRATIONS};
 use crate::crosstyping::*;
+use crate::sync;
 
 
+/// Outcome of `MultiuserDb::submit_expense`.
+pub enum Submitted {
+    Stored(Expense),
+    // Stored by an earlier attempt, of which connected clients were notified back then.
+    Duplicate(Expense),
+}
+
+/// Which expenses `MultiuserDb::list_expenses` returns.
+#[derive(Default)]
+pub struct ExpenseFilter {
+    pub from: Option<OffsetDateTime>,
+    pub to: Option<OffsetDateTime>,
+    /// Empty one stands for unclassified expenses.
+    pub group: Option<String>,
+    pub currency: Option<String>,
+    pub tag: Option<String>,
+    pub include_revoked: bool,
+}
+
+/// Live expenses of a period summed in base currency, overall and by group.
+pub struct Totals {
+    pub total: u64,
+    pub count: usize,
+    pub groups: Vec<(Option<String>, u64, usize)>,
+    /// Every category and its parents, with expenses of their subcategories included; parents
+    /// come before their subcategories.
+    pub rolled_up: Vec<(String, u64, usize)>,
+    /// By tag, largest first; expenses with several tags count for each.
+    pub tags: Vec<(String, u64, usize)>,
+}
+
 pub struct MultiuserDb {
     conn: Mutex<Connection>,
     clients_notify_updates: RwLock<HashMap<String, broadcast::Sender<ClientboundUpdate>>>
@@ -17,34 +54,25 @@ pub struct MultiuserDb {
 
 impl MultiuserDb {
     pub fn mem_new() -> Self {
-        let conn = Connection::open_in_memory().unwrap();
-        
-        conn.execute_batch("
-BEGIN TRANSACTION;
-CREATE TABLE spending_records (
-    id        BLOB PRIMARY KEY  DEFAULT(randomblob(16)),
-    principal TEXT              DEFAULT NULL,
-    unix_date TEXT              DEFAULT(datetime('now')),
-    amount_indivisible INT8,
-    spend_group        TEXT,
-    revoked            BOOL     DEFAULT FALSE
-);
-CREATE INDEX live_records ON spending_records(principal, revoked, unix_date);
-CREATE INDEX aggregate_records ON spending_records(principal, revoked, spend_group, unix_date);
-
-CREATE TABLE users (
-    device    TEXT PRIMARY KEY NOT NULL,
-    principal TEXT             NOT NULL,
-    totp_key  BLOB             DEFAULT(randomblob(24))
-);
-CREATE INDEX enum_devices ON users(principal);
-COMMIT;
-        ").unwrap();
-        
-        Self {
+        Self::with_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+    
+    /// Opens (creating if needed) a file-backed database, so that records outlive the process.
+    pub fn open(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let conn = Connection::open(path)
+            .with_context(|| format!("cannot open database at {}", path.display()))?;
+        Self::with_connection(conn)
+    }
+    
+    fn with_connection(mut conn: Connection) -> Result<Self> {
+        // Only pending steps are applied; an existing file keeps its contents.
+        migrate(&mut conn, SERVER_MIGRATIONS)?;
+        
+        Ok(Self {
             conn: Mutex::new(conn),
             clients_notify_updates: Default::default()
-        }
+        })
     }
     
     async fn register_anew_impl(&self, device: &str, principal: &str) -> Result<Vec<u8>> {
@@ -58,6 +86,7 @@ COMMIT;
                 "device name or principal already in use");
         let totp = tx.query_row("INSERT INTO users(device, principal) VALUES(?1, ?2)
                                  RETURNING totp_key", we, |row| row.get(0).into())?;
+        replace_categories(&tx, Some(principal), &default_categories())?;
         tx.commit()?;
         Ok(totp)
     }
@@ -86,7 +115,8 @@ COMMIT;
                 let totp_key: Vec<u8> = row.get(1)?;
                 Ok((principal, totp_key))
             })
-            .map_err(|e| e.into())
+            .optional()?
+            .context("unknown device")
     }
     
     pub async fn login_impl(&self, device: &str, code: &str) -> Result<String> {
@@ -96,22 +126,46 @@ COMMIT;
         Ok(principal)
     }
     
-    pub async fn submit_expense(&self, principal: &str, d: ClientData, temp_alias: Uuid) -> Result<Expense> {
+    /// Stores an expense, unless one with the same `temp_alias` was already stored for this
+    /// principal; then that one is returned, so that resubmitting after reconnection is safe.
+    /// Expenses are dated now, unless `time` tells otherwise, as for imported ones.
+    pub async fn submit_expense(&self, principal: &str, mut d: ClientData, temp_alias: Uuid,
+            time: Option<OffsetDateTime>) -> Result<Submitted> {
         ensure!(!d.revoked, "submitted expense couldn't be revoked already, before it got ID");
+        d.tags = normalize_tags(d.tags.iter().map(String::as_str));
         
-        let expense = self.conn.lock().await.query_row("
-INSERT INTO spending_records(amount_indivisible, spend_group, principal) VALUES(?1, ?2, ?3)
+        let expense = {
+            let conn = self.conn.lock().await;
+            let stored = conn.query_row("
+SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base, tags
+    FROM spending_records
+    WHERE ifnull(principal, '') = ?1 AND temp_alias = ?2;
+            ", (principal, temp_alias), expense_from_row).optional()?;
+            if let Some(expense) = stored {
+                return Ok(Submitted::Duplicate(expense));
+            }
+            
+            let base_amount = to_base(d.amount, &d.currency, exchange_rate(&conn, principal, &d.currency)?);
+            conn.query_row("
+INSERT INTO spending_records(amount_indivisible, spend_group, principal, note, currency, amount_base,
+                             temp_alias, unix_date, tags)
+    VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ifnull(datetime(?8), datetime('now')), ?9)
     RETURNING id,
               principal,
-              unix_date;
-        ", (d.amount, d.group.clone(), principal), |row| {
-            let server = Metadata {
-                uid:       row.get(0)?,
-                principal: row.get(1)?,
-                time:      row.get(2)?,
-            };
-            Ok(Expense{server, client: d})
-        })?;
+              unix_date,
+              amount_base;
+            ", (d.amount, d.group.clone(), principal, d.note.clone(), d.currency.clone(), base_amount,
+                temp_alias, time, tags_column(&d.tags)),
+            |row| {
+                let server = Metadata {
+                    uid:         row.get(0)?,
+                    principal:   row.get(1)?,
+                    time:        row.get(2)?,
+                    base_amount: row.get(3)?,
+                };
+                Ok(Expense{server, client: d})
+            })?
+        };
         
         // if there are WebSockets or SSEs connected, we must notify them
         if let Some(s) = self.clients_notify_updates.read().await.get(principal) {
@@ -120,33 +174,30 @@ INSERT INTO spending_records(amount_indivisible, spend_group, principal) VALUES(
             });
         }
         
-        Ok(expense)
+        Ok(Submitted::Stored(expense))
     }
     
-    pub async fn submit_revoke(&self, principal: &str, total_id: Uuid) -> Result<Expense> {
+    /// Marks a live expense revoked. Returns `None` if the principal has no such live record.
+    pub async fn submit_revoke(&self, principal: &str, total_id: Uuid) -> Result<Option<Expense>> {
         let expense = self.conn.lock().await.query_row("
-UPDATE spending_records SET revoked = TRUE WHERE principal = ?1 AND id = ?2
+UPDATE spending_records SET revoked = TRUE WHERE principal = ?1 AND id = ?2 AND revoked = FALSE
     RETURNING id,
               principal,
               unix_date,
               amount_indivisible,
               spend_group,
-              revoked;
+              revoked,
+              note,
+              currency,
+              amount_base,
+              tags;
         ", (principal, total_id), |row| {
-            let server = Metadata {
-                uid:       row.get(0)?,
-                principal: row.get(1)?,
-                time:      row.get(2)?,
-            };
-            let client = ClientData {
-                amount:    row.get(3)?,
-                group:     row.get(4)?,
-                revoked:   row.get(5)?
-            };
-            assert!(client.revoked);
+            let expense = expense_from_row(row)?;
+            assert!(expense.client.revoked);
             // ensure!(client.revoked, "database failed to mark the record revoked");
-            Ok(Expense{server, client})
-        })?;
+            Ok(expense)
+        }).optional()?;
+        let Some(expense) = expense else {return Ok(None)};
         
         // if there are WebSockets or SSEs connected, we must notify them
         if let Some(s) = self.clients_notify_updates.read().await.get(principal) {
@@ -155,7 +206,92 @@ UPDATE spending_records SET revoked = TRUE WHERE principal = ?1 AND id = ?2
             });
         }
         
-        Ok(expense)
+        Ok(Some(expense))
+    }
+    
+    /// Makes a revoked expense live again. Returns it, or `None` if the principal has no such
+    /// revoked record.
+    pub async fn submit_restore(&self, principal: &str, total_id: Uuid) -> Result<Option<Expense>> {
+        let expense = self.conn.lock().await.query_row("
+UPDATE spending_records SET revoked = FALSE WHERE principal = ?1 AND id = ?2 AND revoked = TRUE
+    RETURNING id,
+              principal,
+              unix_date,
+              amount_indivisible,
+              spend_group,
+              revoked,
+              note,
+              currency,
+              amount_base,
+              tags;
+        ", (principal, total_id), expense_from_row).optional()?;
+        let Some(expense) = expense else {return Ok(None)};
+        
+        if let Some(s) = self.clients_notify_updates.read().await.get(principal) {
+            let _ = s.send(ClientboundUpdate::Restored {
+                expense: expense.clone()
+            });
+        }
+        
+        Ok(Some(expense))
+    }
+    
+    /// Replaces client data and time of a live expense, keeping previous values in
+    /// `spending_amendments`. Returns previous and amended versions, or `None` if the principal
+    /// has no such live record.
+    pub async fn submit_amend(&self, principal: &str, total_id: Uuid, mut d: ClientData,
+            time: OffsetDateTime) -> Result<Option<(Expense, Expense)>> {
+        ensure!(!d.revoked, "amendment cannot revoke an expense, revocation must be used instead");
+        d.tags = normalize_tags(d.tags.iter().map(String::as_str));
+        
+        let (previous, expense) = {
+            let mut conn = self.conn.lock().await;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            
+            let previous = tx.query_row(
+                "SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base,
+                        tags
+                 FROM spending_records
+                 WHERE principal = ?1 AND id = ?2 AND revoked = FALSE",
+                (principal, total_id), expense_from_row).optional()?;
+            let Some(previous) = previous else {return Ok(None)};
+            
+            let base_amount = to_base(d.amount, &d.currency, exchange_rate(&tx, principal, &d.currency)?);
+            tx.execute("
+INSERT INTO spending_amendments(expense_id, unix_date, amount_indivisible, spend_group, note,
+                                currency, amount_base, tags)
+    SELECT id, unix_date, amount_indivisible, spend_group, note, currency, amount_base, tags
+    FROM spending_records WHERE id = ?1;
+            ", (total_id,))?;
+            let expense = tx.query_row("
+UPDATE spending_records SET amount_indivisible = ?2, spend_group = ?3, unix_date = datetime(?4),
+                            note = ?5, currency = ?6, amount_base = ?7, tags = ?8
+    WHERE id = ?1
+    RETURNING id,
+              principal,
+              unix_date,
+              amount_indivisible,
+              spend_group,
+              revoked,
+              note,
+              currency,
+              amount_base,
+              tags;
+            ", (total_id, d.amount, d.group, time, d.note, d.currency, base_amount, tags_column(&d.tags)),
+            expense_from_row)?;
+            tx.commit()?;
+            
+            (previous, expense)
+        };
+        
+        // if there are WebSockets or SSEs connected, we must notify them
+        if let Some(s) = self.clients_notify_updates.read().await.get(principal) {
+            let _ = s.send(ClientboundUpdate::Amended {
+                previous: previous.clone(), expense: expense.clone()
+            });
+        }
+        
+        Ok(Some((previous, expense)))
     }
     
     pub async fn subscribe(&self, principal: String) -> broadcast::Receiver<ClientboundUpdate> {
@@ -169,57 +305,281 @@ UPDATE spending_records SET revoked = TRUE WHERE principal = ?1 AND id = ?2
     pub async fn load(&self, principal: &str) -> Result<()> {
         let conn = self.conn.lock().await;
         
-        let lifetime_gen: (u64, usize) = conn.query_row(
-            "SELECT SUM(amount_indivisible), COUNT(*) FROM spending_records
-             WHERE principal = ? AND revoked = FALSE", (principal,),
-            |row| {
-                let total: Option<u64> = row.get(0)?;
-                Ok((total.unwrap_or(0), row.get(1)?))
-            },
-        )?;
-        let lifetime_grouped: Vec<(String, u64)> = conn.prepare(
-            "SELECT COALESCE(spend_group, ''), SUM(amount_indivisible) FROM spending_records
-             WHERE principal = ? AND revoked = FALSE GROUP BY spend_group")?.query_map((principal,),
-            |row| {
-                let group: String = row.get(0)?;
-                let total: u64 = row.get(1)?;
-                Ok((group, total))
-            }
-        )?.filter_map(|r| r.ok()).collect::<Vec<_>>();
-        let lifetime_stats = (lifetime_gen, lifetime_grouped);
+        let lifetime_stats = group_totals(&conn, Some(principal), None, None)?;
         
-        if MONTH_LIKE != time::Duration::days(30) {
-            eprintln!("code was refactored and now accumulates data for non-30-day interval");
-            eprintln!("please fix src/server/sqlite.rs : MultiUserDb::load too");
-        }
+        // Clients count the calendar month in their own timezone, so expenses are sent from a day
+        // before the earliest time it could have started anywhere.
         let recent_expenses: Vec<Expense> = conn.prepare(
-            "SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked 
+            "SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base,
+                    tags
              FROM spending_records 
-             WHERE principal = ? AND revoked = FALSE AND unix_date >= date('now', '-30 days')
+             WHERE principal = ? AND revoked = FALSE
+               AND unix_date >= date('now', '-1 day', 'start of month', '-1 day')
              ORDER BY unix_date ASC",
-        )?.query_map((principal,),
-            |row| {
-                let server = Metadata {
-                    uid:       row.get(0)?,
-                    principal: row.get(1)?,
-                    time:      row.get(2)?,
-                };
-                let client = ClientData {
-                    amount:    row.get(3)?,
-                    group:     row.get(4)?,
-                    revoked:   row.get(5)?
-                };
-                Ok(Expense{server, client})
-            }
-        )?.filter_map(|r| r.ok()).collect::<Vec<_>>();
+        )?.query_map((principal,), expense_from_row)?.filter_map(|r| r.ok()).collect::<Vec<_>>();
         
+        let rates = exchange_rates(&conn, principal)?;
+        let categories = categories_of(&conn, Some(principal))?;
+        let budgets = budgets_of(&conn, Some(principal))?;
         std::mem::drop(conn);
         
         // if there are WebSockets or SSEs connected, we must notify them
         if let Some(s) = self.clients_notify_updates.read().await.get(principal) {
-            let _ = s.send(ClientboundUpdate::InitStats {lifetime_stats, recent_expenses});
+            let _ = s.send(ClientboundUpdate::InitStats {lifetime_stats, recent_expenses, categories});
+            let _ = s.send(ClientboundUpdate::ExchangeRates {rates});
+            let _ = s.send(ClientboundUpdate::Budgets {budgets});
         }
         Ok(())
     }
+    
+    /// Stores the principal's rate for `currency`; expenses recorded earlier keep their
+    /// converted amounts.
+    pub async fn set_exchange_rate(&self, principal: &str, currency: &str, base_per_unit: f64)
+            -> Result<()> {
+        ensure!(is_currency_code(currency) && currency != BASE_CURRENCY,
+                "{currency:?} is not a foreign currency code");
+        ensure!(base_per_unit.is_finite() && base_per_unit > 0.0, "exchange rate must be positive");
+        
+        let rates = {
+            let mut conn = self.conn.lock().await;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            tx.execute("DELETE FROM exchange_rates WHERE principal = ?1 AND currency = ?2",
+                       (principal, currency))?;
+            tx.execute("INSERT INTO exchange_rates(principal, currency, base_per_unit)
+                        VALUES(?1, ?2, ?3)", (principal, currency, base_per_unit))?;
+            let rates = exchange_rates(&tx, principal)?;
+            tx.commit()?;
+            rates
+        };
+        
+        // if there are WebSockets or SSEs connected, we must notify them
+        if let Some(s) = self.clients_notify_updates.read().await.get(principal) {
+            let _ = s.send(ClientboundUpdate::ExchangeRates {rates});
+        }
+        Ok(())
+    }
+    
+    /// Replaces the principal's categories; expenses recorded earlier keep their groups.
+    pub async fn set_categories(&self, principal: &str, categories: Vec<Category>) -> Result<()> {
+        ensure!(categories_valid(&categories),
+                "categories must have distinct names and icons, at most {MAX_CATEGORIES} of them");
+        
+        {
+            let mut conn = self.conn.lock().await;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            replace_categories(&tx, Some(principal), &categories)?;
+            tx.commit()?;
+        }
+        
+        // if there are WebSockets or SSEs connected, we must notify them
+        if let Some(s) = self.clients_notify_updates.read().await.get(principal) {
+            let _ = s.send(ClientboundUpdate::Categories {categories});
+        }
+        Ok(())
+    }
+    
+    /// Replaces the principal's monthly budgets.
+    pub async fn set_budgets(&self, principal: &str, budgets: Vec<Budget>) -> Result<()> {
+        ensure!(budgets_valid(&budgets), "budgets must have nonzero limits, at most one for each category");
+        
+        let budgets = {
+            let mut conn = self.conn.lock().await;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            replace_budgets(&tx, Some(principal), &budgets)?;
+            let budgets = budgets_of(&tx, Some(principal))?;
+            tx.commit()?;
+            budgets
+        };
+        
+        // if there are WebSockets or SSEs connected, we must notify them
+        if let Some(s) = self.clients_notify_updates.read().await.get(principal) {
+            let _ = s.send(ClientboundUpdate::Budgets {budgets});
+        }
+        Ok(())
+    }
+    
+    /// Up to `amount` most recent live expenses preceding `before` in (time, id) order, oldest first.
+    pub async fn query_history(&self, principal: &str, before: (OffsetDateTime, Uuid), amount: usize)
+            -> Result<Vec<Expense>> {
+        let conn = self.conn.lock().await;
+        let mut expenses = conn.prepare(
+            "SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base,
+                    tags
+             FROM spending_records 
+             WHERE principal = ?1 AND revoked = FALSE AND (unix_date, id) < (datetime(?2), ?3)
+             ORDER BY unix_date DESC, id DESC
+             LIMIT ?4",
+        )?.query_map((principal, before.0, before.1, amount), expense_from_row)?.collect::<Result<Vec<_>, _>>()?;
+        expenses.reverse();
+        Ok(expenses)
+    }
+    
+    /// Like `query_history`, but only expenses with `tag`; also tells totals of all of those.
+    pub async fn query_tagged(&self, principal: &str, tag: &str, before: OffsetDateTime, amount: usize)
+            -> Result<(Vec<Expense>, (u64, usize))> {
+        let conn = self.conn.lock().await;
+        let mut expenses = conn.prepare(
+            "SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base,
+                    tags
+             FROM spending_records
+             WHERE principal = ?1 AND revoked = FALSE AND unix_date <= datetime(?2)
+               AND id IN (SELECT expense_id FROM expense_tags WHERE tag = ?3)
+             ORDER BY unix_date DESC
+             LIMIT ?4",
+        )?.query_map((principal, before, tag, amount), expense_from_row)?.collect::<Result<Vec<_>, _>>()?;
+        expenses.reverse();
+        Ok((expenses, tag_total(&conn, Some(principal), tag)?))
+    }
+    
+    /// Up to `amount` expenses of the principal following `after` in (time, id) order, revoked
+    /// ones only if asked. Pages are read one by one, so that exports do not hold the database.
+    pub async fn export_page(&self, principal: &str, include_revoked: bool,
+            after: Option<(OffsetDateTime, Uuid)>, amount: usize) -> Result<Vec<Expense>> {
+        let conn = self.conn.lock().await;
+        let (after_time, after_id) = after.unzip();
+        let expenses = conn.prepare(
+            "SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base,
+                    tags
+             FROM spending_records
+             WHERE principal = ?1 AND (?2 OR revoked = FALSE)
+               AND (?3 IS NULL OR (unix_date, id) > (datetime(?3), ?4))
+             ORDER BY unix_date, id
+             LIMIT ?5",
+        )?.query_map((principal, include_revoked, after_time, after_id, amount), expense_from_row)?
+          .collect::<Result<Vec<_>, _>>()?;
+        Ok(expenses)
+    }
+    
+    /// Up to `amount` expenses of the principal matching `filter`, newest first, continuing
+    /// before `before` in (time, id) order.
+    pub async fn list_expenses(&self, principal: &str, filter: &ExpenseFilter,
+            before: Option<(OffsetDateTime, Uuid)>, amount: usize) -> Result<Vec<Expense>> {
+        let conn = self.conn.lock().await;
+        let (before_time, before_id) = before.unzip();
+        let expenses = conn.prepare(
+            "SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base,
+                    tags
+             FROM spending_records
+             WHERE principal = ?1 AND (?2 OR revoked = FALSE)
+               AND (?3 IS NULL OR unix_date >= datetime(?3)) AND (?4 IS NULL OR unix_date < datetime(?4))
+               AND (?5 IS NULL OR ifnull(spend_group, '') = ?5) AND (?6 IS NULL OR currency = ?6)
+               AND (?7 IS NULL OR (unix_date, id) < (datetime(?7), ?8))
+               AND (?10 IS NULL OR id IN (SELECT expense_id FROM expense_tags WHERE tag = ?10))
+             ORDER BY unix_date DESC, id DESC
+             LIMIT ?9",
+        )?.query_map((principal, filter.include_revoked, filter.from, filter.to, &filter.group,
+                      &filter.currency, before_time, before_id, amount, &filter.tag), expense_from_row)?
+          .collect::<Result<Vec<_>, _>>()?;
+        Ok(expenses)
+    }
+    
+    /// Sums live expenses of the principal made in `[from, to)`, by group and by tag, as sent to
+    /// clients; open ends are not limited.
+    pub async fn period_stats(&self, principal: &str, from: Option<OffsetDateTime>, to: Option<OffsetDateTime>)
+            -> Result<(GroupTotals, Vec<(String, u64, usize)>)> {
+        let conn = self.conn.lock().await;
+        Ok((group_totals(&conn, Some(principal), from, to)?, tag_totals(&conn, Some(principal), from, to)?))
+    }
+    
+    /// Sums live expenses of the principal made in `[from, to)`; open ends are not limited.
+    pub async fn totals(&self, principal: &str, from: Option<OffsetDateTime>, to: Option<OffsetDateTime>)
+            -> Result<Totals> {
+        let conn = self.conn.lock().await;
+        let groups = conn.prepare(
+            "SELECT spend_group, SUM(amount_base), COUNT(*) FROM spending_records
+             WHERE principal = ?1 AND revoked = FALSE
+               AND (?2 IS NULL OR unix_date >= datetime(?2)) AND (?3 IS NULL OR unix_date < datetime(?3))
+             GROUP BY spend_group
+             ORDER BY SUM(amount_base) DESC",
+        )?.query_map((principal, from, to), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+          .collect::<Result<Vec<(Option<String>, u64, usize)>, _>>()?;
+        // Each group is split into paths of its parents and itself: `rest` is what is left to
+        // append to `path`, separators included.
+        let rolled_up = conn.prepare(
+            "WITH RECURSIVE paths(path, rest, amount) AS (
+                 SELECT '', spend_group || '/', amount_base FROM spending_records
+                 WHERE principal = ?1 AND revoked = FALSE AND spend_group IS NOT NULL
+                   AND (?2 IS NULL OR unix_date >= datetime(?2)) AND (?3 IS NULL OR unix_date < datetime(?3))
+                 UNION ALL
+                 SELECT path || iif(path = '', '', '/') || substr(rest, 1, instr(rest, '/') - 1),
+                        substr(rest, instr(rest, '/') + 1), amount
+                 FROM paths WHERE rest <> ''
+             )
+             SELECT path, SUM(amount), COUNT(*) FROM paths
+             WHERE path <> ''
+             GROUP BY path
+             ORDER BY path",
+        )?.query_map((principal, from, to), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+          .collect::<Result<Vec<_>, _>>()?;
+        let tags = tag_totals(&conn, Some(principal), from, to)?;
+        Ok(Totals {
+            total: groups.iter().map(|g| g.1).sum(),
+            count: groups.iter().map(|g| g.2).sum(),
+            groups,
+            rolled_up,
+            tags,
+        })
+    }
+    
+    /// Merges records changed on a self-hosted database, replying with the principal's records
+    /// changed after `request.since`. Connected clients are sent a fresh snapshot if anything
+    /// changed.
+    pub async fn sync(&self, principal: &str, request: SyncRequest) -> Result<SyncReply> {
+        let (reply, changed) = {
+            let mut conn = self.conn.lock().await;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let mut changed = false;
+            for expense in &request.changes {
+                changed |= sync::merge(&tx, Some(principal), expense, Some(request.since))?;
+            }
+            let reply = sync::changes_since(&tx, Some(principal), request.since)?;
+            tx.commit()?;
+            (reply, changed)
+        };
+        
+        if changed {
+            self.load(principal).await?;
+        }
+        Ok(reply)
+    }
 }
 
+
+/// Maps an `id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency,
+/// amount_base, tags` row.
+fn expense_from_row(row: &Row<'_>) -> rusqlite::Result<Expense> {
+    let server = Metadata {
+        uid:         row.get(0)?,
+        principal:   row.get(1)?,
+        time:        row.get(2)?,
+        base_amount: row.get(8)?,
+    };
+    let client = ClientData {
+        amount:    row.get(3)?,
+        group:     row.get(4)?,
+        revoked:   row.get(5)?,
+        note:      row.get(6)?,
+        currency:  row.get(7)?,
+        tags:      tags_from_column(&row.get::<_, String>(9)?),
+    };
+    Ok(Expense{server, client})
+}
+
+/// Rate of `currency` in base currency units per unit, as set by the principal.
+fn exchange_rate(conn: &Connection, principal: &str, currency: &str) -> Result<f64> {
+    if currency == BASE_CURRENCY {
+        return Ok(1.0);
+    }
+    conn.query_row("SELECT base_per_unit FROM exchange_rates WHERE principal = ?1 AND currency = ?2",
+                   (principal, currency), |row| row.get(0))
+        .optional()?
+        .with_context(|| format!("no exchange rate for {currency}"))
+}
+
+fn exchange_rates(conn: &Connection, principal: &str) -> Result<Vec<(String, f64)>> {
+    let rates = conn.prepare("SELECT currency, base_per_unit FROM exchange_rates
+                              WHERE principal = ? ORDER BY currency")?
+        .query_map((principal,), |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(rates)
+}
@@ -0,0 +1,25 @@
+// #[sides(server, client#selfhost)]
+
+use rusqlite::Connection;
+use time::OffsetDateTime;
+use anyhow::Result;
+
+use crate::crosstyping::GroupTotals;
+
+
+/// Live expenses of `principal` (`None` for self-hosted ones) made in `[from, to)`, open ends
+/// unlimited: their total in base currency and count, and totals by group, unclassified ones
+/// under an empty name.
+pub fn group_totals(conn: &Connection, principal: Option<&str>, from: Option<OffsetDateTime>,
+        to: Option<OffsetDateTime>) -> Result<GroupTotals> {
+    let groups = conn.prepare("
+SELECT COALESCE(spend_group, ''), SUM(amount_base), COUNT(*) FROM spending_records
+    WHERE principal IS ?1 AND revoked = FALSE
+      AND (?2 IS NULL OR unix_date >= datetime(?2)) AND (?3 IS NULL OR unix_date < datetime(?3))
+    GROUP BY spend_group;
+        ")?.query_map((principal, from, to), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<Vec<(String, u64, usize)>, _>>()?;
+    let total = groups.iter().map(|g| g.1).sum();
+    let count = groups.iter().map(|g| g.2).sum();
+    Ok(((total, count), groups.into_iter().map(|(group, amount, _)| (group, amount)).collect()))
+}
@@ -0,0 +1,326 @@
+// #[sides(server, client#selfhost)]
+
+use rusqlite::{Connection, OptionalExtension, Row};
+use anyhow::{ensure, Result};
+
+use crate::tags::{tags_column, tags_from_column};
+use crate::crosstyping::*;
+
+
+// Both sides apply the other's records with `merge`, so the rules below hold wherever a record
+// ends up:
+// - records are matched by `id`; unknown ones are stored as they are;
+// - revocation always wins, and a revoked record is never brought back;
+// - when both sides amended a record, server's version stands: server keeps its own content if
+//   it changed since the client last pulled, or once it is revoked, and the client takes whatever
+//   server sends, revoked or not.
+
+/// Maps an `id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency,
+/// amount_base, changed, tags` row.
+fn record_from_row(row: &Row<'_>) -> rusqlite::Result<(Expense, u64)> {
+    let server = Metadata {
+        uid:         row.get(0)?,
+        principal:   row.get(1)?,
+        time:        row.get(2)?,
+        base_amount: row.get(8)?,
+    };
+    let client = ClientData {
+        amount:    row.get(3)?,
+        group:     row.get(4)?,
+        revoked:   row.get(5)?,
+        note:      row.get(6)?,
+        currency:  row.get(7)?,
+        tags:      tags_from_column(&row.get::<_, String>(10)?),
+    };
+    Ok((Expense{server, client}, row.get(9)?))
+}
+
+fn same_content(a: &Expense, b: &Expense) -> bool {
+    a.server.time == b.server.time && a.server.base_amount == b.server.base_amount &&
+        a.client.amount == b.client.amount && a.client.group == b.client.group &&
+        a.client.note == b.client.note && a.client.currency == b.client.currency &&
+        a.client.tags == b.client.tags
+}
+
+pub fn change_counter(conn: &Connection) -> Result<u64> {
+    Ok(conn.query_row("SELECT value FROM change_counter", (), |row| row.get(0))?)
+}
+
+/// Records of `principal` (`None` for self-hosted ones) changed after `since`, oldest change
+/// first, along with the current change counter.
+pub fn changes_since(conn: &Connection, principal: Option<&str>, since: u64) -> Result<SyncReply> {
+    let cursor = change_counter(conn)?;
+    let changes = conn.prepare("
+SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base,
+       changed, tags
+    FROM spending_records
+    WHERE principal IS ?1 AND changed > ?2
+    ORDER BY changed;
+        ")?.query_map((principal, since), record_from_row)?
+        .map(|r| r.map(|(expense, _)| expense))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(SyncReply {cursor, changes})
+}
+
+/// Applies a record from the other side. `ours_since` is given on server only: the client's
+/// cursor, after which server's own amendments take precedence over incoming ones.
+/// Returns whether anything was changed.
+pub fn merge(conn: &Connection, principal: Option<&str>, incoming: &Expense, ours_since: Option<u64>)
+        -> Result<bool> {
+    let ours = conn.query_row("
+SELECT id, principal, unix_date, amount_indivisible, spend_group, revoked, note, currency, amount_base,
+       changed, tags
+    FROM spending_records
+    WHERE id = ?1;
+        ", (incoming.server.uid,), record_from_row).optional()?;
+    
+    let Some((ours, changed)) = ours else {
+        let (s, c) = (&incoming.server, &incoming.client);
+        conn.execute("
+INSERT INTO spending_records(id, principal, unix_date, amount_indivisible, spend_group, revoked, note,
+                             currency, amount_base, tags)
+    VALUES(?1, ?2, datetime(?3), ?4, ?5, ?6, ?7, ?8, ?9, ?10);
+            ", (s.uid, principal, s.time, c.amount, &c.group, c.revoked, &c.note, &c.currency, s.base_amount,
+                tags_column(&c.tags)))?;
+        return Ok(true);
+    };
+    ensure!(ours.server.principal.as_deref() == principal,
+            "record {} belongs to someone else", incoming.server.uid);
+    
+    let keep_ours = ours_since.is_some_and(|since| changed > since || ours.client.revoked);
+    let amend = !keep_ours && !same_content(&ours, incoming);
+    let revoke = incoming.client.revoked && !ours.client.revoked;
+    
+    if amend {
+        let (s, c) = (&incoming.server, &incoming.client);
+        conn.execute("
+INSERT INTO spending_amendments(expense_id, unix_date, amount_indivisible, spend_group, note,
+                                currency, amount_base, tags)
+    SELECT id, unix_date, amount_indivisible, spend_group, note, currency, amount_base, tags
+    FROM spending_records WHERE id = ?1;
+            ", (s.uid,))?;
+        conn.execute("
+UPDATE spending_records SET amount_indivisible = ?2, spend_group = ?3, unix_date = datetime(?4),
+                            note = ?5, currency = ?6, amount_base = ?7, tags = ?8
+    WHERE id = ?1;
+            ", (s.uid, c.amount, &c.group, s.time, &c.note, &c.currency, s.base_amount, tags_column(&c.tags)))?;
+    }
+    if revoke {
+        conn.execute("UPDATE spending_records SET revoked = TRUE WHERE id = ?1",
+                     (incoming.server.uid,))?;
+    }
+    Ok(amend || revoke)
+}
+
+
+// ---------------------------------------------------------------------------------------------- //
+// Self-hosted side, pushing its changes to a server and pulling server's ones. Server tests build
+// it too, to synchronize with a server run in-process.
+
+#[cfg(any(feature = "selfhost", test))]
+pub use client::*;
+
+#[cfg(any(feature = "selfhost", test))]
+mod client {
+    use reqwest::Client;
+    use anyhow::{ensure, Context, Result};
+    use postcard::{to_stdvec, from_bytes};
+    use rusqlite::{Connection, OptionalExtension, TransactionBehavior};
+    #[cfg(feature = "selfhost")]
+    use {anyhow::bail, std::path::PathBuf};
+    
+    use super::{change_counter, changes_since, merge};
+    use crate::crosstyping::*;
+
+
+    #[cfg(feature = "selfhost")]
+    const USAGE: &str = "\
+Usage: ting-expense-a sync SERVER DEVICE [--database FILE]
+
+Merges the local database with records of DEVICE's principal on SERVER, e.g. http://host:4341.
+The login code is generated from TEA_TOTP_SECRET (base32) if set, otherwise it is asked for.";
+
+    /// Records sent and received by one synchronization.
+    pub struct Synced {
+        pub pushed: usize,
+        pub pulled: usize,
+    }
+    
+    /// Logs in to `api_base` as `device` and exchanges changes made since the previous
+    /// synchronization with that server.
+    pub async fn sync_with(conn: &mut Connection, api_base: &str, device: &str, code: &str) -> Result<Synced> {
+        let http = Client::builder().cookie_store(true).build()?;
+        let response = http.post(format!("{api_base}/api/login/{device}")).body(code.trim().to_owned())
+            .send().await.with_context(|| format!("server {api_base} is unreachable"))?;
+        let status = response.status();
+        ensure!(status.is_success(), "{}", response.text().await?);
+        
+        let (pulled, pushed): (u64, u64) = conn.query_row(
+            "SELECT pulled, pushed FROM sync_peers WHERE server = ?1", (api_base,),
+            |row| Ok((row.get(0)?, row.get(1)?))).optional()?.unwrap_or_default();
+        let outgoing = changes_since(conn, None, pushed)?;
+        let request = SyncRequest {since: pulled, changes: outgoing.changes};
+        
+        let response = http.post(format!("{api_base}/api/sync")).body(to_stdvec(&request)?)
+            .send().await.with_context(|| format!("server {api_base} is unreachable"))?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        ensure!(status.is_success(), "{}", String::from_utf8_lossy(&body));
+        let reply: SyncReply = from_bytes(&body).context("server sent malformed changes")?;
+        
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let before = change_counter(&tx)?;
+        for expense in &reply.changes {
+            merge(&tx, None, expense, None)?;
+        }
+        // Merged records need not go back to server; unless something else was changed locally
+        // while we waited, then they go along with it next time.
+        let pushed = match before == outgoing.cursor {
+            true  => change_counter(&tx)?,
+            false => outgoing.cursor,
+        };
+        tx.execute("INSERT OR REPLACE INTO sync_peers(server, pulled, pushed) VALUES(?1, ?2, ?3)",
+                   (api_base, reply.cursor, pushed))?;
+        tx.commit()?;
+        
+        Ok(Synced {pushed: request.changes.len(), pulled: reply.changes.len()})
+    }
+    
+    /// Runs `ting-expense-a sync ...` command line.
+    #[cfg(feature = "selfhost")]
+    pub fn run_command(args: &[String]) -> Result<()> {
+        let mut positional = vec![];
+        let mut database = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" | "-h" => {
+                    println!("{USAGE}");
+                    return Ok(());
+                },
+                "--database" => database = Some(PathBuf::from(args.next().context(USAGE)?)),
+                flag if flag.starts_with("--") => bail!("unknown flag {flag}\n\n{USAGE}"),
+                _ => positional.push(arg.as_str()),
+            }
+        }
+        let [api_base, device] = positional[..] else {bail!("{USAGE}")};
+        let api_base = api_base.trim_end_matches('/');
+        let path = database.or_else(crate::selfhost::default_path)
+            .context("no storage directory for the database")?;
+        
+        let code = match std::env::var("TEA_TOTP_SECRET") {
+            Ok(secret) => {
+                let secret = totp_rs::Secret::Encoded(secret.trim().to_uppercase()).to_bytes()
+                    .map_err(|_| anyhow::anyhow!("TEA_TOTP_SECRET is not valid base32"))?;
+                totp_rs::TOTP::new(totp_rs::Algorithm::SHA1, 8, 1, 20, secret)?.generate_current()?
+            },
+            Err(_) => {
+                eprint!("Code from authenticator for {device}: ");
+                let mut code = String::new();
+                std::io::stdin().read_line(&mut code)?;
+                code
+            },
+        };
+        
+        let mut conn = crate::selfhost::open_connection(&path)?;
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let synced = runtime.block_on(sync_with(&mut conn, api_base, device, &code))?;
+        println!("Sent {} changed records to {api_base}, received {}.", synced.pushed, synced.pulled);
+        Ok(())
+    }
+}
+
+
+// ---------------------------------------------------------------------------------------------- //
+// Fresh self-hosted databases synchronized with an in-memory server.
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use rusqlite::Connection;
+    use uuid::Uuid;
+    
+    use super::{changes_since, sync_with};
+    use crate::migrations::{migrate, SELFHOST_MIGRATIONS};
+    use crate::server::{serve_forever, ServerConfig};
+    use crate::crosstyping::*;
+    
+    const DEVICE: &str = "laptop";
+    
+    /// Serves on a free local port, returning its address and a new device's TOTP secret.
+    async fn start_server() -> (String, Vec<u8>) {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let bind = format!("127.0.0.1:{port}");
+        tokio::spawn(serve_forever(ServerConfig::ephemeral(&bind), None));
+        
+        let api_base = format!("http://{bind}");
+        let register = format!("{api_base}/api/register/{DEVICE}");
+        for _ in 0..50 {
+            if let Ok(response) = reqwest::Client::new().post(&register).body("tester").send().await {
+                assert!(response.status().is_success());
+                return (api_base, response.bytes().await.unwrap().to_vec());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        panic!("server did not start on {bind}");
+    }
+    
+    fn selfhosted() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn, SELFHOST_MIGRATIONS).unwrap();
+        conn
+    }
+    
+    fn spend(conn: &Connection, amount: u64, note: &str) -> Uuid {
+        conn.query_row("
+INSERT INTO spending_records(amount_indivisible, note, currency, amount_base) VALUES(?1, ?2, 'RUB', ?1)
+    RETURNING id;
+            ", (amount, note), |row| row.get(0)).unwrap()
+    }
+    
+    fn record(conn: &Connection, id: Uuid) -> Expense {
+        changes_since(conn, None, 0).unwrap().changes.into_iter().find(|e| e.server.uid == id).unwrap()
+    }
+    
+    async fn sync(conn: &mut Connection, api_base: &str, secret: &[u8]) -> (usize, usize) {
+        let code = totp_rs::TOTP::new(totp_rs::Algorithm::SHA1, 8, 1, 20, secret.to_vec()).unwrap()
+            .generate_current().unwrap();
+        let synced = sync_with(conn, api_base, DEVICE, &code).await.unwrap();
+        (synced.pushed, synced.pulled)
+    }
+    
+    #[test]
+    fn sync_with_server() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (api_base, secret) = start_server().await;
+            let (mut a, mut b) = (selfhosted(), selfhosted());
+            
+            // Push from one database, pull into another.
+            let first = spend(&a, 100, "first");
+            let second = spend(&a, 200, "second");
+            assert_eq!(sync(&mut a, &api_base, &secret).await.0, 2);
+            assert_eq!(sync(&mut b, &api_base, &secret).await, (0, 2));
+            assert_eq!(record(&b, first).client.note.as_deref(), Some("first"));
+            assert_eq!(record(&b, second).client.amount, 200);
+            assert_eq!(sync(&mut a, &api_base, &secret).await, (0, 0));
+            
+            // Revocation wins over a concurrent amendment.
+            b.execute("UPDATE spending_records SET note = 'amended' WHERE id = ?1", (first,)).unwrap();
+            a.execute("UPDATE spending_records SET revoked = TRUE WHERE id = ?1", (first,)).unwrap();
+            sync(&mut b, &api_base, &secret).await;
+            sync(&mut a, &api_base, &secret).await;
+            sync(&mut b, &api_base, &secret).await;
+            assert!(record(&a, first).client.revoked);
+            assert!(record(&b, first).client.revoked);
+            
+            // Server's version stands if it was amended since the other side pulled.
+            b.execute("UPDATE spending_records SET note = 'server' WHERE id = ?1", (second,)).unwrap();
+            sync(&mut b, &api_base, &secret).await;
+            a.execute("UPDATE spending_records SET note = 'late' WHERE id = ?1", (second,)).unwrap();
+            sync(&mut a, &api_base, &secret).await;
+            sync(&mut b, &api_base, &secret).await;
+            assert_eq!(record(&a, second).client.note.as_deref(), Some("server"));
+            assert_eq!(record(&b, second).client.note.as_deref(), Some("server"));
+        });
+    }
+}
@@ -0,0 +1,40 @@
+// #[sides(server, client#selfhost)]
+
+use rusqlite::Connection;
+use anyhow::Result;
+
+
+/// Tags as kept in the `tags` column of records: a JSON array.
+pub fn tags_column(tags: &[String]) -> String {
+    serde_json::to_string(tags).unwrap()
+}
+
+/// Reads the `tags` column; anything malformed reads as no tags.
+pub fn tags_from_column(text: &str) -> Vec<String> {
+    serde_json::from_str(text).unwrap_or_default()
+}
+
+/// Live expenses of `principal` (`None` for self-hosted ones) made in `[from, to)`, if given,
+/// summed in base currency by tag, largest first. Expenses with several tags count for each.
+pub fn tag_totals(conn: &Connection, principal: Option<&str>, from: Option<time::OffsetDateTime>,
+        to: Option<time::OffsetDateTime>) -> Result<Vec<(String, u64, usize)>> {
+    let totals = conn.prepare("
+SELECT expense_tags.tag, SUM(amount_base), COUNT(*)
+    FROM expense_tags JOIN spending_records ON spending_records.id = expense_tags.expense_id
+    WHERE principal IS ?1 AND revoked = FALSE
+      AND (?2 IS NULL OR unix_date >= datetime(?2)) AND (?3 IS NULL OR unix_date < datetime(?3))
+    GROUP BY expense_tags.tag
+    ORDER BY SUM(amount_base) DESC, expense_tags.tag;
+        ")?.query_map((principal, from, to), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(totals)
+}
+
+/// All live expenses of `principal` with `tag`, summed in base currency, and their count.
+pub fn tag_total(conn: &Connection, principal: Option<&str>, tag: &str) -> Result<(u64, usize)> {
+    Ok(conn.query_row("
+SELECT ifnull(SUM(amount_base), 0), COUNT(*)
+    FROM expense_tags JOIN spending_records ON spending_records.id = expense_tags.expense_id
+    WHERE principal IS ?1 AND revoked = FALSE AND expense_tags.tag = ?2;
+        ", (principal, tag), |row| Ok((row.get(0)?, row.get(1)?)))?)
+}
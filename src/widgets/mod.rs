@@ -5,22 +5,44 @@ pub use ecs::expense_category_slider;
 pub use pie::pie_chart_with_legend;
 
 
+/// What user asked to do with an expense row.
+pub enum RowAction {
+    Revoke(time::OffsetDateTime, uuid::Uuid),
+    Amend(crate::crosstyping::Expense),
+}
+
 /// Creates a representation of given Ok(expense) or Err(fact that it's not
-/// loaded yet) on given ui, using single widget.
-pub fn show_spending_mayload(ui: &mut egui::Ui, ml: crate::db_slice::MayLoad<'_>) {
+/// loaded yet) on given ui, using single widget (and edit/revoke buttons for
+/// confirmed expenses). Returns the action user requested on that expense.
+pub fn show_spending_mayload(ui: &mut egui::Ui, ml: crate::db_slice::MayLoad<'_>)
+        -> Option<RowAction> {
     use time::format_description::well_known::Rfc3339;
-    use crate::crosstyping::UNCLASSIFIED;
+    use crate::crosstyping::{currency_sign, format_amount, UNCLASSIFIED};
     use crate::db_slice::MayLoad::*;
     
     match ml {
-        Confirmed(e) => ui.monospace(e.to_string()),
-        NotLoaded    => ui.monospace("------------------------------"),
-        Provisional{data, temp_time} =>
-            ui.monospace(format!("[не синхронизировано!] - {} - {}\u{20bd} на {}",
+        Confirmed(e) => ui.horizontal(|ui| {
+            ui.monospace(e.to_string());
+            let amend = ui.small_button("✏").on_hover_text("Исправить запись");
+            let revoke = ui.small_button("🗑").on_hover_text("Удалить запись");
+            if amend.clicked() {
+                Some(RowAction::Amend(e.clone()))
+            } else {
+                revoke.clicked().then_some(RowAction::Revoke(e.server.time, e.server.uid))
+            }
+        }).inner,
+        NotLoaded    => {
+            ui.monospace("------------------------------");
+            None
+        },
+        Provisional{data, temp_time} => {
+            let note = data.note.as_deref().map(|n| format!(" ({n})")).unwrap_or_default();
+            let tags: String = data.tags.iter().map(|t| format!(" #{t}")).collect();
+            ui.monospace(format!("[не синхронизировано!] - {} - {}{} на {}{}{}",
                 temp_time.format(&Rfc3339).unwrap(),
-                data.amount, data.group.as_deref().unwrap_or(UNCLASSIFIED))),
-    };
+                format_amount(data.amount, &data.currency), currency_sign(&data.currency),
+                data.group.as_deref().unwrap_or(UNCLASSIFIED), tags, note));
+            None
+        },
+    }
 }
-
-
-